@@ -0,0 +1,1575 @@
+use std::{collections::HashMap, ops::RangeInclusive};
+
+use crate::{
+    code::Code,
+    diagnostic::{Diagnostic, ExpansionKind, Location},
+    expr::{check_symbol, Expr},
+    isa::Isa,
+    listing::Listing,
+    options::{AllocOrder, Options},
+    parser::*,
+    symbol_table::{SymbolKind, SymbolTable},
+    target::Target,
+};
+
+/// The largest value an A-instruction can load: its MSB must stay 0,
+/// otherwise the CPU would execute the word as a C-instruction.
+pub const MAX_A_VALUE: usize = 0x7fff;
+
+/// Translate the program read by `parser` into Hack machine code,
+/// one 16 bit binary word per instruction.
+#[cfg(test)]
+pub fn assemble(parser: Parser, options: &Options) -> Result<Vec<String>, Diagnostic> {
+    assemble_program(parser, options).map(|assembled| assembled.words)
+}
+
+/// The result of assembling a program.
+pub struct Assembled {
+    pub words: Vec<String>,
+    pub listing: Listing,
+    /// Problems that don't stop the assembly, like `.warning`.
+    pub warnings: Vec<Diagnostic>,
+    /// The number of words of the program, before `--pad`.
+    pub rom_size: usize,
+    /// The variables with their addresses, in address order.
+    pub variables: Vec<(String, RangeInclusive<usize>)>,
+}
+
+impl Assembled {
+    /// How much of the ROM and of the RAM for variables the program uses,
+    /// printed with `--report`.
+    pub fn report(&self, target: &Target) -> String {
+        let (first, last) = (target.variables.start(), target.variables.end());
+        let used = match (self.variables.first(), self.variables.last()) {
+            (Some((_, low)), Some((_, high))) => format!(", at {} to {}", low.start(), high.end()),
+            _ => String::new(),
+        };
+        let words = self
+            .variables
+            .iter()
+            .map(|(_, addresses)| addresses.clone().count())
+            .sum::<usize>();
+        format!(
+            "ROM: {} of {} words used\n\
+             RAM: {words} of {} words used by variables, from {first} to {last}{used}\n",
+            self.rom_size,
+            MAX_A_VALUE + 1,
+            last - first + 1,
+        )
+    }
+}
+
+/// Translate the program read by `parser` into Hack machine code, one 16
+/// bit binary word per instruction, with its listing and warnings.
+pub fn assemble_program(parser: Parser, options: &Options) -> Result<Assembled, Diagnostic> {
+    let mut symbol_table = SymbolTable::new(&options.target);
+    let mut words = Vec::new();
+    let mut listing = Listing::default();
+    let mut warnings = Vec::new();
+    let mut parser = parser;
+    parser.preprocess(options)?;
+    for (name, value) in &options.defines {
+        symbol_table.add_entry(name, *value, SymbolKind::Constant, None);
+    }
+
+    // first pass
+    let mut first_pass = parser.clone();
+    // the variables declared with `.var`, with their size
+    let mut declared = Vec::new();
+    let mut anonymous_labels = AnonymousLabels::default();
+    loop {
+        if !first_pass.has_more_lines() {
+            break;
+        }
+        first_pass.advance();
+        if let Some(ins) = &first_pass.current_instruction {
+            let location = first_pass.location();
+            let error = |message: String| Diagnostic::new(location.clone(), message);
+            first_pass.check().map_err(error)?;
+            match ins.ins_type {
+                // add to symbol table
+                InstructionType::LInstruction => {
+                    let symbol = first_pass.qualify(&first_pass.symbol()).map_err(error)?;
+                    if first_pass.next_ins_address > MAX_A_VALUE {
+                        return Err(error(format!(
+                            "label `{symbol}` is at address {}, past the end of the {} word ROM",
+                            first_pass.next_ins_address,
+                            MAX_A_VALUE + 1
+                        )));
+                    }
+                    if is_anonymous_label(&symbol) {
+                        anonymous_labels
+                            .define(&symbol, first_pass.next_ins_address)
+                            .map_err(error)?;
+                        continue;
+                    }
+                    match symbol_table.get(&symbol) {
+                        Some(defined) if defined.kind == SymbolKind::Constant => {
+                            return Err(error(format!(
+                                "label `{symbol}` conflicts with the constant defined on {}",
+                                defined.defined_on()
+                            )));
+                        }
+                        // legacy code may rely on the first definition winning
+                        Some(defined) => {
+                            let message = match defined.kind {
+                                SymbolKind::Predefined => {
+                                    format!("label `{symbol}` has the name of a predefined symbol")
+                                }
+                                _ => format!(
+                                    "label `{symbol}` is already defined on {}",
+                                    defined.defined_on()
+                                ),
+                            };
+                            if !options.legacy_labels {
+                                return Err(error(message));
+                            }
+                            warnings.push(error(format!("{message}, ignored")));
+                        }
+                        None => symbol_table.add_entry(
+                            &symbol,
+                            first_pass.next_ins_address,
+                            SymbolKind::Label,
+                            Some(location),
+                        ),
+                    }
+                }
+                InstructionType::Directive => {
+                    let (name, _) = first_pass.directive();
+                    match name.as_str() {
+                        "equ" | "define" => define_constant(&mut symbol_table, &first_pass)?,
+                        "var" => {
+                            let (name, size) = var_args(&symbol_table, &first_pass)?;
+                            declared.push((name, size, location.clone()));
+                        }
+                        "word" | "assert" => (),
+                        "error" => return Err(error(message(&first_pass)?)),
+                        "warning" => warnings.push(error(message(&first_pass)?)),
+                        "org" => {
+                            let (address, _) = org_args(&symbol_table, &first_pass, options)?;
+                            first_pass.next_ins_address = address;
+                        }
+                        _ => return Err(error(format!("unknown directive `.{name}`"))),
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let sizes = allocate_declared(&mut symbol_table, &declared, &parser, options)?;
+
+    // second pass
+    loop {
+        if !parser.has_more_lines() {
+            break;
+        }
+        parser.advance();
+        if let Some(ins) = &parser.current_instruction {
+            use InstructionType::*;
+            let address = words.len();
+            let mut text = ins.text().to_string();
+            match ins.ins_type {
+                AInstruction => {
+                    let symbol = parser.symbol();
+                    let location = parser.location();
+                    let error = |message: String| Diagnostic::new(location.clone(), message);
+                    // the symbol is an expression of numbers and symbols:
+                    // if a symbol in symbol table, then translate to it's value
+                    // if not in, then add (symbol, alloc_pos) to table, alloc_pos++
+                    let value = if is_anonymous_label(&symbol) {
+                        let label = anonymous_labels.resolve(&symbol, address).map_err(error)?;
+                        label as i64
+                    } else {
+                        let expr = Expr::parse(&symbol).map_err(error)?;
+                        expr.eval(&mut |name| {
+                            let symbol = parser.qualify(name)?;
+                            if !symbol_table.contains(&symbol) {
+                                if name.starts_with('.') && !sizes.contains_key(&symbol) {
+                                    return Err(format!(
+                                        "undefined local symbol `{symbol}`, local symbols \
+                                         aren't allocated as variables"
+                                    ));
+                                }
+                                if options.strict && !sizes.contains_key(&symbol) {
+                                    return Err(format!(
+                                        "undefined symbol `{symbol}`, and `--strict` needs \
+                                         variables declared with `.var`"
+                                    ));
+                                }
+                                let size = sizes.get(&symbol).copied().unwrap_or(1);
+                                symbol_table.add_variable(&symbol, size, Some(location.clone()))?;
+                            }
+                            Ok(symbol_table.get_address(&symbol) as i64)
+                        })
+                        .map_err(error)?
+                    };
+                    let addr = usize::try_from(value)
+                        .ok()
+                        .filter(|&addr| addr <= MAX_A_VALUE)
+                        .ok_or_else(|| {
+                            error(format!(
+                                "`@{symbol}` is {value}, but A-instructions can only load values from 0 to {MAX_A_VALUE}"
+                            ))
+                        })?;
+                    words.push(format!("{:016b}", addr));
+                }
+                CInstruction => {
+                    let error = |message: String| Diagnostic::new(parser.location(), message);
+                    let dest = parser.dest();
+                    let (dest, dest_bin) = options
+                        .isa
+                        .dest(&dest)
+                        .ok_or_else(|| error(format!("invalid destination `{dest}`")))?;
+                    let (comp, comp_bin) = encode_comp(&parser.comp(), options).map_err(error)?;
+                    let jump = parser.jump();
+                    let (jump, jump_bin) = options
+                        .isa
+                        .jump(&jump)
+                        .ok_or_else(|| error(format!("invalid jump `{jump}`")))?;
+                    words.push(format!("{comp_bin}{dest_bin}{jump_bin}"));
+                    // the listing shows the canonical spelling
+                    text = match (dest.is_empty(), jump.is_empty()) {
+                        (true, true) => comp,
+                        (true, false) => format!("{comp};{jump}"),
+                        (false, true) => format!("{dest}={comp}"),
+                        (false, false) => format!("{dest}={comp};{jump}"),
+                    };
+                }
+                Directive if parser.directive().0 == "org" => {
+                    let (org, fill) = org_args(&symbol_table, &parser, options)?;
+                    parser.next_ins_address = org;
+                    words.resize(org, format!("{fill:016b}"));
+                    // the padding would drown the listing
+                    listing.push(address, &[], ins.text());
+                    continue;
+                }
+                Directive if parser.directive().0 == "var" => {
+                    // allocated here unless `--alloc-order` allocated it already
+                    let (name, size) = var_args(&symbol_table, &parser)?;
+                    if !symbol_table.contains(&name) {
+                        symbol_table
+                            .add_variable(&name, size, Some(parser.location()))
+                            .map_err(|e| Diagnostic::new(parser.location(), e))?;
+                    }
+                }
+                Directive if parser.directive().0 == "assert" => {
+                    check_assertion(&symbol_table, &parser)?;
+                }
+                Directive if parser.directive().0 == "word" => {
+                    for value in parser.directive().1.split(',') {
+                        words.push(format!(
+                            "{:016b}",
+                            word_value(&symbol_table, &parser, value)?
+                        ));
+                    }
+                }
+                _ => (),
+            }
+            if words.len() > MAX_A_VALUE + 1 {
+                return Err(Diagnostic::new(
+                    parser.location(),
+                    format!(
+                        "the program doesn't fit in the {} word ROM",
+                        MAX_A_VALUE + 1
+                    ),
+                ));
+            }
+            match parser.location().expansion {
+                Some(expansion) if expansion.kind == ExpansionKind::PseudoOp => {
+                    listing.push_pseudo_op(&expansion, address, &words[address..]);
+                }
+                _ => listing.push(address, &words[address..], &text),
+            }
+        }
+    }
+
+    let rom_size = words.len();
+    if options.pad {
+        words.resize(MAX_A_VALUE + 1, format!("{:016b}", options.fill));
+    }
+    let mut variables = symbol_table.variables_allocated;
+    variables.sort_by_key(|(_, addresses)| *addresses.start());
+
+    Ok(Assembled {
+        words,
+        listing,
+        warnings,
+        rom_size,
+        variables,
+    })
+}
+
+/// Encode the computation of a C-instruction: return its canonical
+/// spelling, and the prefix and comp bits.
+fn encode_comp(comp: &str, options: &Options) -> Result<(String, String), String> {
+    let isa = &options.isa;
+    let found = |(mnemonic, bin): (&str, &str)| (mnemonic.to_string(), bin.to_string());
+    if let Some(encoded) = isa.comp(comp) {
+        return Ok(found(encoded));
+    }
+    if let Some(canonical) = Code::normalize_comp(comp, options.full_alu) {
+        if let Some(encoded) = isa.comp(&canonical) {
+            return Ok(found(encoded));
+        }
+        // `--full-alu` computations are C-instructions of the Hack ALU
+        if let Some(bin) = Code::full_comp(&canonical).filter(|_| options.full_alu) {
+            return Ok((canonical, format!("111{bin}")));
+        }
+    }
+    if Isa::extended().comp(comp).is_some() {
+        return Err(format!(
+            "`{comp}` is a shift of the extended ISA, it needs `--isa extended`"
+        ));
+    }
+    if !options.full_alu
+        && Code::normalize_comp(comp, false).is_none()
+        && Code::normalize_comp(comp, true).is_some()
+    {
+        return Err(format!(
+            "`{comp}` is not a documented ALU computation, it needs `--full-alu`"
+        ));
+    }
+    Err(format!("unknown computation `{comp}`"))
+}
+
+/// Evaluate an argument of `.word`, `.org` or `.assert`, which can use the labels and
+/// constants defined so far.
+fn eval_defined(
+    symbol_table: &SymbolTable,
+    parser: &Parser,
+    value: &str,
+) -> Result<i64, Diagnostic> {
+    let error = |message: String| Diagnostic::new(parser.location(), message);
+    let value = value.trim();
+    if value.is_empty() {
+        let (directive, _) = parser.directive();
+        return Err(error(format!("`.{directive}` needs a value")));
+    }
+    Expr::parse(value)
+        .and_then(|expr| {
+            expr.eval(&mut |symbol| {
+                let symbol = parser.qualify(symbol)?;
+                symbol_table
+                    .get(&symbol)
+                    .map(|defined| defined.value as i64)
+                    .ok_or_else(|| format!("undefined symbol `{symbol}`"))
+            })
+        })
+        .map_err(error)
+}
+
+/// Evaluate a value of `.word`, or the fill word of `.org`.
+fn word_value(symbol_table: &SymbolTable, parser: &Parser, value: &str) -> Result<u16, Diagnostic> {
+    let value = eval_defined(symbol_table, parser, value)?;
+    u16::try_from(value).map_err(|_| {
+        let (directive, _) = parser.directive();
+        Diagnostic::new(
+            parser.location(),
+            format!("`.{directive}` value is {value}, but words must be from 0 to 65535"),
+        )
+    })
+}
+
+/// The quoted message of `.error "message"` or `.warning "message"`.
+fn message(parser: &Parser) -> Result<String, Diagnostic> {
+    let (directive, args) = parser.directive();
+    unquote(&args).map(String::from).ok_or_else(|| {
+        Diagnostic::new(
+            parser.location(),
+            format!("`.{directive}` needs a quoted message"),
+        )
+    })
+}
+
+/// Check `.assert EXPR` or `.assert EXPR, "message"`, once every label is
+/// known: the assertion fails if EXPR is 0.
+fn check_assertion(symbol_table: &SymbolTable, parser: &Parser) -> Result<(), Diagnostic> {
+    let (_, args) = parser.directive();
+    let (expr, message) = match args.split_once('"') {
+        Some((expr, _)) => {
+            let message = expr
+                .trim_end()
+                .strip_suffix(',')
+                .and_then(|_| unquote(&args[expr.len()..]))
+                .ok_or_else(|| {
+                    Diagnostic::new(parser.location(), "`.assert` needs a quoted message")
+                })?;
+            (expr.trim_end().trim_end_matches(','), Some(message))
+        }
+        None => (args.as_str(), None),
+    };
+    if eval_defined(symbol_table, parser, expr)? != 0 {
+        return Ok(());
+    }
+    let message = match message {
+        Some(message) => format!("assertion `{expr}` failed: {message}"),
+        None => format!("assertion `{expr}` failed"),
+    };
+    Err(Diagnostic::new(parser.location(), message))
+}
+
+/// Evaluate `.org ADDRESS, FILL`: the address the next instruction is
+/// placed at, and the word filling the ROM up to it.
+fn org_args(
+    symbol_table: &SymbolTable,
+    parser: &Parser,
+    options: &Options,
+) -> Result<(usize, u16), Diagnostic> {
+    let (_, args) = parser.directive();
+    let (address, fill) = match args.split_once(',') {
+        Some((address, fill)) => (address, Some(fill)),
+        None => (args.as_str(), None),
+    };
+    let fill = match fill {
+        Some(fill) => word_value(symbol_table, parser, fill)?,
+        None => options.fill,
+    };
+    let address = eval_defined(symbol_table, parser, address)?;
+    let current = parser.next_ins_address;
+    match usize::try_from(address) {
+        Ok(address) if address > MAX_A_VALUE + 1 => Err(Diagnostic::new(
+            parser.location(),
+            format!(
+                "`.org` address {address} is past the end of the {} word ROM",
+                MAX_A_VALUE + 1
+            ),
+        )),
+        Ok(address) if address >= current => Ok((address, fill)),
+        _ => Err(Diagnostic::new(
+            parser.location(),
+            format!("`.org` address {address} is before the current address {current}"),
+        )),
+    }
+}
+
+/// The addresses of the anonymous `(+)` and `(-)` labels, in order.
+#[derive(Default)]
+struct AnonymousLabels {
+    forward: Vec<usize>,
+    backward: Vec<usize>,
+}
+
+impl AnonymousLabels {
+    fn define(&mut self, label: &str, address: usize) -> Result<(), String> {
+        match label {
+            "+" => self.forward.push(address),
+            "-" => self.backward.push(address),
+            _ => {
+                return Err(format!(
+                    "anonymous label `({label})` must be `(+)` or `(-)`"
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the address `@+`, `@++`, `@-`... refers to from the instruction
+    /// at `address`: `@++` is the second `(+)` after it, `@-` the nearest
+    /// `(-)` before it (or on it).
+    fn resolve(&self, symbol: &str, address: usize) -> Result<usize, String> {
+        let n = symbol.len();
+        let label = if symbol.starts_with('+') {
+            let mut after = self.forward.iter().filter(|&&label| label > address);
+            after.nth(n - 1)
+        } else {
+            let mut before = self
+                .backward
+                .iter()
+                .rev()
+                .filter(|&&label| label <= address);
+            before.nth(n - 1)
+        };
+        label.copied().ok_or_else(|| {
+            let (label, direction) = match symbol.starts_with('+') {
+                true => ("(+)", "after"),
+                false => ("(-)", "before"),
+            };
+            match n {
+                1 => format!("`@{symbol}` has no `{label}` label {direction} it"),
+                _ => format!("`@{symbol}` needs {n} `{label}` labels {direction} it"),
+            }
+        })
+    }
+}
+
+/// The name and size of `.var NAME` or `.var NAME, SIZE`. The size is an
+/// expression of constants and labels defined before it, 1 by default.
+fn var_args(symbol_table: &SymbolTable, parser: &Parser) -> Result<(String, usize), Diagnostic> {
+    let (_, args) = parser.directive();
+    let error = |message: String| Diagnostic::new(parser.location(), message);
+    let (name, size) = match args.split_once(',') {
+        Some((name, size)) => (name.trim(), Some(size)),
+        None => (args.trim(), None),
+    };
+    if name.is_empty() {
+        return Err(error("`.var` needs a variable name".to_string()));
+    }
+    check_symbol("variable", name).map_err(error)?;
+    let name = parser.qualify(name).map_err(error)?;
+    let size = match size {
+        Some(size) => eval_defined(symbol_table, parser, size)?,
+        None => 1,
+    };
+    let size = usize::try_from(size)
+        .ok()
+        .filter(|&size| (1..=MAX_A_VALUE + 1).contains(&size))
+        .ok_or_else(|| {
+            error(format!(
+                "variable `{name}` has {size} words, but it must have from 1 to {}",
+                MAX_A_VALUE + 1
+            ))
+        })?;
+    Ok((name, size))
+}
+
+/// Check the variables declared with `.var` once every label is known, and
+/// allocate them now if `--alloc-order` asks for it. Return the size of each.
+fn allocate_declared(
+    symbol_table: &mut SymbolTable,
+    declared: &[(String, usize, Location)],
+    parser: &Parser,
+    options: &Options,
+) -> Result<HashMap<String, usize>, Diagnostic> {
+    let mut sizes = HashMap::new();
+    for (i, (name, size, location)) in declared.iter().enumerate() {
+        let error = |message: String| Diagnostic::new(location.clone(), message);
+        if let Some((_, _, first)) = declared[..i].iter().find(|(other, ..)| other == name) {
+            return Err(error(format!(
+                "variable `{name}` is already declared on {first}"
+            )));
+        }
+        symbol_table.check_undefined(name).map_err(error)?;
+        sizes.insert(name.clone(), *size);
+    }
+
+    let variables = match options.alloc_order {
+        AllocOrder::Usage => return Ok(sizes),
+        AllocOrder::Declaration => declared.to_vec(),
+        AllocOrder::Alphabetical => {
+            let mut variables = declared.to_vec();
+            if !options.strict {
+                variables.extend(
+                    undeclared_variables(symbol_table, &sizes, parser)
+                        .into_iter()
+                        .map(|(name, location)| (name, 1, location)),
+                );
+            }
+            variables.sort_by(|(a, ..), (b, ..)| a.cmp(b));
+            variables
+        }
+    };
+    for (name, size, location) in variables {
+        symbol_table
+            .add_variable(&name, size, Some(location.clone()))
+            .map_err(|e| Diagnostic::new(location, e))?;
+    }
+    Ok(sizes)
+}
+
+/// The symbols used by A-instructions that are neither defined nor
+/// declared, so are variables, with where they are first used.
+fn undeclared_variables(
+    symbol_table: &SymbolTable,
+    declared: &HashMap<String, usize>,
+    parser: &Parser,
+) -> Vec<(String, Location)> {
+    let mut parser = parser.clone();
+    let mut variables: Vec<(String, Location)> = Vec::new();
+    while parser.has_more_lines() {
+        parser.advance();
+        if parser
+            .current_instruction
+            .as_ref()
+            .is_none_or(|ins| ins.ins_type != InstructionType::AInstruction)
+        {
+            continue;
+        }
+        let symbol = parser.symbol();
+        if is_anonymous_label(&symbol) {
+            continue;
+        }
+        // errors are reported by the second pass
+        let Ok(expr) = Expr::parse(&symbol) else {
+            continue;
+        };
+        let _ = expr.eval(&mut |name| {
+            let symbol = parser.qualify(name)?;
+            if !name.starts_with('.')
+                && !symbol_table.contains(&symbol)
+                && !declared.contains_key(&symbol)
+                && !variables.iter().any(|(name, _)| *name == symbol)
+            {
+                variables.push((symbol, parser.location()));
+            }
+            Ok(0)
+        });
+    }
+    variables
+}
+
+/// Handle `.equ NAME value` (or `.define NAME value`): add the constant
+/// `NAME` to the symbol table. The value is an expression that can use
+/// constants and labels defined before it.
+fn define_constant(symbol_table: &mut SymbolTable, parser: &Parser) -> Result<(), Diagnostic> {
+    let (directive, args) = parser.directive();
+    let location = parser.location();
+    let error = |message: String| Diagnostic::new(location.clone(), message);
+    let (name, value) = split_constant(&args)
+        .ok_or_else(|| error(format!("`.{directive}` needs a name and a value")))?;
+    check_symbol("constant", name).map_err(error)?;
+    let name = &parser.qualify(name).map_err(error)?;
+    symbol_table.check_undefined(name).map_err(error)?;
+
+    let expr = Expr::parse(value).map_err(error)?;
+    let value = expr
+        .eval(&mut |symbol| {
+            let symbol = parser.qualify(symbol)?;
+            symbol_table
+                .get(&symbol)
+                .map(|defined| defined.value as i64)
+                .ok_or_else(|| format!("undefined symbol `{symbol}`"))
+        })
+        .map_err(error)?;
+    let value = usize::try_from(value)
+        .ok()
+        .filter(|&value| value <= u16::MAX as usize)
+        .ok_or_else(|| {
+            error(format!(
+                "constant `{name}` is {value}, but constants must be from 0 to 65535"
+            ))
+        })?;
+    symbol_table.add_entry(name, value, SymbolKind::Constant, Some(location));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, fs, path::Path};
+
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    fn assemble_file(name: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
+        let dir = Path::new("test_files");
+        let words = assemble(
+            Parser::new(&dir.join(format!("{name}.asm")))?,
+            &Options::default(),
+        )?;
+        let expected = fs::read_to_string(dir.join(format!("{name}.hack")))?
+            .lines()
+            .map(String::from)
+            .collect();
+        Ok((words, expected))
+    }
+
+    fn assemble_source(source: &str) -> Result<Vec<String>, Diagnostic> {
+        assemble(Parser::from_source(source), &Options::default())
+    }
+
+    #[test]
+    fn test_test_files() -> TestResult {
+        for name in ["Add", "Max", "MaxL", "Rect", "RectL", "Pong", "PongL"] {
+            let (words, expected) = assemble_file(name)?;
+            assert_eq!(words, expected, "{name}.asm");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_numeric_literals() -> TestResult {
+        let words = assemble_source("@0x4000\n@0b1111\n@'A'\n@24576")?;
+
+        assert_eq!(
+            words,
+            [
+                "0100000000000000",
+                "0000000000001111",
+                "0000000001000001",
+                "0110000000000000"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_a_value_range() {
+        assert_eq!(
+            assemble_source("@32767\n@0x7FFF"),
+            Ok(vec!["0111111111111111".to_string(); 2])
+        );
+        assert_eq!(
+            assemble_source("@32767\n@40000"),
+            Err(Diagnostic::new(
+                2,
+                "`@40000` is 40000, but A-instructions can only load values from 0 to 32767"
+            ))
+        );
+        assert_eq!(
+            assemble_source("@0x8000"),
+            Err(Diagnostic::new(
+                1,
+                "`@0x8000` is 32768, but A-instructions can only load values from 0 to 32767"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_expressions() -> TestResult {
+        let source = "@SCREEN+32\n(LOOP)\n@LOOP-1\n@KBD*2/4\n@(END+1)*2\n@ARR+5\n@ARR\n(END)";
+        let words = assemble_source(source)?;
+
+        assert_eq!(
+            words,
+            [
+                format!("{:016b}", 16384 + 32),
+                format!("{:016b}", 0),
+                format!("{:016b}", 12288),
+                format!("{:016b}", 14),
+                format!("{:016b}", 16 + 5),
+                format!("{:016b}", 16),
+            ]
+        );
+        assert_eq!(
+            assemble_source("@1\n@SCREEN-16385"),
+            Err(Diagnostic::new(
+                2,
+                "`@SCREEN-16385` is -1, but A-instructions can only load values from 0 to 32767"
+            ))
+        );
+        assert_eq!(
+            assemble_source("@SCREEN+"),
+            Err(Diagnostic::new(
+                1,
+                "missing operand in expression `SCREEN+`"
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_constants() -> TestResult {
+        let source = ".equ ROW_WORDS 32\n.define ROWS, 256\n.equ SCREEN_END SCREEN + ROW_WORDS*ROWS\n@SCREEN_END-1\n@ROW_WORDS\n@x";
+        let words = assemble_source(source)?;
+
+        assert_eq!(
+            words,
+            [
+                format!("{:016b}", 24575),
+                format!("{:016b}", 32),
+                format!("{:016b}", 16),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_constant_errors() {
+        assert_eq!(
+            assemble_source(".equ A 1\n.equ A 2"),
+            Err(Diagnostic::new(2, "`A` is already defined on line 1"))
+        );
+        assert_eq!(
+            assemble_source("(LOOP)\n.equ LOOP 2"),
+            Err(Diagnostic::new(2, "`LOOP` is already defined on line 1"))
+        );
+        assert_eq!(
+            assemble_source(".equ LOOP 2\n(LOOP)"),
+            Err(Diagnostic::new(
+                2,
+                "label `LOOP` conflicts with the constant defined on line 1"
+            ))
+        );
+        assert_eq!(
+            assemble_source(".equ SP 256"),
+            Err(Diagnostic::new(
+                1,
+                "`SP` is a predefined symbol and can't be redefined"
+            ))
+        );
+        assert_eq!(
+            assemble_source(".equ R0 1"),
+            Err(Diagnostic::new(
+                1,
+                "`R0` is a predefined symbol and can't be redefined"
+            ))
+        );
+        assert_eq!(
+            assemble_source(".equ WIDTH"),
+            Err(Diagnostic::new(1, "`.equ` needs a name and a value"))
+        );
+        assert_eq!(
+            assemble_source(".equ 1X 1"),
+            Err(Diagnostic::new(1, "constant `1X` can't start with a digit"))
+        );
+        assert_eq!(
+            assemble_source(".equ X END\n(END)"),
+            Err(Diagnostic::new(1, "undefined symbol `END`"))
+        );
+        assert_eq!(
+            assemble_source(".equ X -1"),
+            Err(Diagnostic::new(
+                1,
+                "constant `X` is -1, but constants must be from 0 to 65535"
+            ))
+        );
+        assert_eq!(
+            assemble_source(".eq X 1"),
+            Err(Diagnostic::new(1, "unknown directive `.eq`"))
+        );
+    }
+
+    #[test]
+    fn test_macros() -> TestResult {
+        let source = "\
+.macro PUSH_D
+   @SP
+   AM=M+1
+   A=A-1
+   M=D
+.endm
+.macro PUSH_CONST value
+   @\\value
+   D=A
+   PUSH_D
+.endm
+   PUSH_CONST 7
+(END)
+   @END
+   0;JMP";
+        let words = assemble_source(source)?;
+
+        assert_eq!(words.len(), 8);
+        assert_eq!(words[0], format!("{:016b}", 7));
+        assert_eq!(words[6], format!("{:016b}", 6));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_macro_diagnostic() {
+        let source = ".macro LOAD value\n   @\\value\n.endm\n   LOAD 1\n   LOAD 0x8000";
+
+        assert_eq!(
+            assemble_source(source).map_err(|e| e.to_string()),
+            Err("line 2: `@0x8000` is 32768, but A-instructions can only load values from 0 to 32767\n  in macro `LOAD` called on line 5".to_string())
+        );
+    }
+
+    #[test]
+    fn test_local_labels() -> TestResult {
+        let source = "\
+(Foo.bar)
+(.loop)
+   @.loop
+   0;JMP
+(Foo.baz)
+.equ .SIZE 2
+   @.loop+.SIZE
+(.loop)
+   @Foo.bar.loop
+   @.SIZE";
+        let words = assemble_source(source)?;
+
+        assert_eq!(
+            words,
+            [
+                format!("{:016b}", 0),
+                "1110101010000111".to_string(),
+                format!("{:016b}", 3 + 2),
+                format!("{:016b}", 0),
+                format!("{:016b}", 2),
+            ]
+        );
+        assert_eq!(
+            assemble_source("(Foo)\n   @.tmp"),
+            Err(Diagnostic::new(
+                2,
+                "undefined local symbol `Foo.tmp`, local symbols aren't allocated as variables"
+            ))
+        );
+        assert_eq!(
+            assemble_source("@1\n(.loop)"),
+            Err(Diagnostic::new(
+                2,
+                "local symbol `.loop` is used before any global label"
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_macro_labels_keep_the_scope() -> TestResult {
+        let source = "\
+.macro SKIPZ
+   @SKIP_\\@
+   D;JEQ
+(SKIP_\\@)
+.endm
+(Foo)
+(.loop)
+   SKIPZ
+   @.loop
+   0;JMP";
+        let words = assemble_source(source)?;
+
+        assert_eq!(words[2], format!("{:016b}", 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_conditionals() -> TestResult {
+        let source = "\
+.ifdef DEBUG
+   @DEBUG
+   D=A
+.endif
+(END)
+   @END
+   0;JMP";
+        let options = Options {
+            defines: vec![("DEBUG".to_string(), 3)],
+            ..Options::default()
+        };
+
+        assert_eq!(
+            assemble(Parser::from_source(source), &options)?,
+            [
+                format!("{:016b}", 3),
+                "1110110000010000".to_string(),
+                format!("{:016b}", 2),
+                "1110101010000111".to_string(),
+            ]
+        );
+        // the skipped block takes no room
+        assert_eq!(
+            assemble_source(source)?,
+            [format!("{:016b}", 0), "1110101010000111".to_string()]
+        );
+        assert_eq!(
+            assemble(Parser::from_source(".equ DEBUG 1"), &options),
+            Err(Diagnostic::new(
+                1,
+                "`DEBUG` is already defined on the command line"
+            ))
+        );
+        assert_eq!(
+            assemble(Parser::from_source("@1\n(DEBUG)"), &options),
+            Err(Diagnostic::new(
+                2,
+                "label `DEBUG` conflicts with the constant defined on the command line"
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_anonymous_labels() -> TestResult {
+        let source = "\
+(-)
+   @+
+   D;JEQ
+   @-
+   0;JMP
+(+)
+(-)
+   @++
+   @--
+   @-
+(+)
+(+)";
+        let words = assemble_source(source)?;
+
+        assert_eq!(
+            words,
+            [
+                format!("{:016b}", 4),
+                "1110001100000010".to_string(),
+                format!("{:016b}", 0),
+                "1110101010000111".to_string(),
+                format!("{:016b}", 7),
+                format!("{:016b}", 0),
+                format!("{:016b}", 4),
+            ]
+        );
+        assert_eq!(
+            assemble_source("(-)\n@--"),
+            Err(Diagnostic::new(2, "`@--` needs 2 `(-)` labels before it"))
+        );
+        assert_eq!(
+            assemble_source("@+\n(-)"),
+            Err(Diagnostic::new(1, "`@+` has no `(+)` label after it"))
+        );
+        assert_eq!(
+            assemble_source("(++)"),
+            Err(Diagnostic::new(
+                1,
+                "anonymous label `(++)` must be `(+)` or `(-)`"
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_word() -> TestResult {
+        let source = "\
+.equ TWO 2
+   @TABLE
+   0;JMP
+(TABLE)
+.word 0xEC10, 0b1, TABLE+TWO
+(END)
+   @END";
+        let Assembled { words, listing, .. } =
+            assemble_program(Parser::from_source(source), &Options::default())?;
+
+        assert_eq!(
+            words,
+            [
+                format!("{:016b}", 2),
+                "1110101010000111".to_string(),
+                "1110110000010000".to_string(),
+                format!("{:016b}", 1),
+                format!("{:016b}", 4),
+                format!("{:016b}", 5),
+            ]
+        );
+        assert_eq!(listing.lines[4].address, 2);
+        assert_eq!(listing.lines[4].words, words[2..5]);
+        assert_eq!(listing.lines[4].text, ".word 0xEC10, 0b1, TABLE+TWO");
+        assert_eq!(
+            assemble_source(".word -1"),
+            Err(Diagnostic::new(
+                1,
+                "`.word` value is -1, but words must be from 0 to 65535"
+            ))
+        );
+        assert_eq!(
+            assemble_source(".word 1,"),
+            Err(Diagnostic::new(1, "`.word` needs a value"))
+        );
+        assert_eq!(
+            assemble_source(".word X"),
+            Err(Diagnostic::new(1, "undefined symbol `X`"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_org() -> TestResult {
+        let source = "\
+   @ENTRY
+   0;JMP
+.equ BASE 4
+.org BASE
+(ENTRY)
+   @ENTRY
+.org ENTRY+3, 0xEA87
+   D=A";
+        let words = assemble_source(source)?;
+
+        assert_eq!(
+            words,
+            [
+                format!("{:016b}", 4),
+                "1110101010000111".to_string(),
+                format!("{:016b}", 0),
+                format!("{:016b}", 0),
+                format!("{:016b}", 4),
+                "1110101010000111".to_string(),
+                "1110101010000111".to_string(),
+                "1110110000010000".to_string(),
+            ]
+        );
+        assert_eq!(
+            assemble_source("@1\n@2\n.org 1"),
+            Err(Diagnostic::new(
+                3,
+                "`.org` address 1 is before the current address 2"
+            ))
+        );
+        assert_eq!(
+            assemble_source(".org 0x8001"),
+            Err(Diagnostic::new(
+                1,
+                "`.org` address 32769 is past the end of the 32768 word ROM"
+            ))
+        );
+        assert_eq!(
+            assemble_source(".org 2, -1"),
+            Err(Diagnostic::new(
+                1,
+                "`.org` value is -1, but words must be from 0 to 65535"
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pad() -> TestResult {
+        let options = Options {
+            pad: true,
+            fill: 0xffff,
+            ..Options::default()
+        };
+        let words = assemble(Parser::from_source("@1\n.org 3"), &options)?;
+
+        assert_eq!(words.len(), 32768);
+        assert_eq!(words[0], format!("{:016b}", 1));
+        assert!(words[1..].iter().all(|word| word == "1111111111111111"));
+
+        for pad in [false, true] {
+            let options = Options {
+                pad,
+                ..Options::default()
+            };
+            assert_eq!(
+                assemble(Parser::from_source(".org 32767\n@1\n.word 2, 3"), &options),
+                Err(Diagnostic::new(
+                    3,
+                    "the program doesn't fit in the 32768 word ROM"
+                ))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_assertions() -> TestResult {
+        let source = "\
+.equ BUFFER 100
+   @END
+(END)
+.assert END < 0x1000
+.assert BUFFER+64 <= SCREEN, \"buffer, too big\"
+.warning \"not optimized\"";
+        let assembled = assemble_program(Parser::from_source(source), &Options::default())?;
+
+        assert_eq!(assembled.words, [format!("{:016b}", 1)]);
+        assert_eq!(assembled.warnings, [Diagnostic::new(6, "not optimized")]);
+        assert_eq!(
+            assemble_source("(END)\n.assert END > 0"),
+            Err(Diagnostic::new(2, "assertion `END > 0` failed"))
+        );
+        assert_eq!(
+            assemble_source(".assert 1 == 2, \"one, two\""),
+            Err(Diagnostic::new(1, "assertion `1 == 2` failed: one, two"))
+        );
+        assert_eq!(
+            assemble_source(".assert 0 \"no comma\""),
+            Err(Diagnostic::new(1, "`.assert` needs a quoted message"))
+        );
+        assert_eq!(
+            assemble_source(".assert X"),
+            Err(Diagnostic::new(1, "undefined symbol `X`"))
+        );
+        assert_eq!(
+            assemble_source("@1\n.error \"unsupported\"\n@2"),
+            Err(Diagnostic::new(2, "unsupported"))
+        );
+        assert_eq!(
+            assemble_source(".error \"see http://x\" // why"),
+            Err(Diagnostic::new(1, "see http://x"))
+        );
+        assert_eq!(
+            assemble_source(".warning oops"),
+            Err(Diagnostic::new(1, "`.warning` needs a quoted message"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pseudo_ops() -> TestResult {
+        let source = "\
+(LOOP)
+   ld D, 5
+   push D // comment
+   jeq D, LOOP
+   inc M
+   goto LOOP";
+        let options = Options {
+            pseudo_ops: true,
+            ..Options::default()
+        };
+        let Assembled { words, listing, .. } =
+            assemble_program(Parser::from_source(source), &options)?;
+
+        assert_eq!(words.len(), 2 + 5 + 2 + 1 + 2);
+        assert_eq!(
+            words[..2],
+            [format!("{:016b}", 5), "1110110000010000".to_string()]
+        );
+        assert_eq!(
+            listing
+                .lines
+                .iter()
+                .map(|line| (line.address, line.words.len(), line.text.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                (0, 0, "(LOOP)"),
+                (0, 2, "ld D, 5"),
+                (2, 5, "push D"),
+                (7, 2, "jeq D, LOOP"),
+                (9, 1, "inc M"),
+                (10, 2, "goto LOOP"),
+            ]
+        );
+        assert_eq!(
+            assemble(Parser::from_source("@1\nld A, 0x8000"), &options)
+                .map_err(|e| e.to_string()),
+            Err("line 2: `@0x8000` is 32768, but A-instructions can only load values from 0 to 32767
+  in pseudo-instruction `ld A, 0x8000` on line 2"
+                .to_string())
+        );
+        assert_eq!(
+            assemble(Parser::from_source("jeq M, END"), &options),
+            Err(Diagnostic::new(1, "`jeq` works on `D`, not `M`"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_full_alu() -> TestResult {
+        let source = "D=D+A+1\nAM=!(D|M);JMP\nD=comp(0b0001010)";
+        let options = Options {
+            full_alu: true,
+            ..Options::default()
+        };
+
+        assert_eq!(
+            assemble(Parser::from_source(source), &options)?,
+            ["1110010111010000", "1111010100101111", "1110001010010000"]
+        );
+        assert_eq!(
+            assemble_source("D=D+A+1"),
+            Err(Diagnostic::new(
+                1,
+                "`D+A+1` is not a documented ALU computation, it needs `--full-alu`"
+            ))
+        );
+        assert_eq!(
+            assemble(Parser::from_source("D=comp(0b10000000)"), &options),
+            Err(Diagnostic::new(1, "unknown computation `comp(0b10000000)`"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalized_c_instructions() -> TestResult {
+        let source = "D = M + 1\nMA=1+D ; JGT\nDAM=A&D\nM|D;JMP";
+        let Assembled { words, listing, .. } =
+            assemble_program(Parser::from_source(source), &Options::default())?;
+
+        assert_eq!(
+            words,
+            assemble_source("D=M+1\nAM=D+1;JGT\nAMD=D&A\nD|M;JMP")?
+        );
+        assert_eq!(
+            listing
+                .lines
+                .iter()
+                .map(|line| line.text.as_str())
+                .collect::<Vec<_>>(),
+            ["D=M+1", "AM=D+1;JGT", "AMD=D&A", "D|M;JMP"]
+        );
+        assert_eq!(
+            assemble_source("D=D+2"),
+            Err(Diagnostic::new(1, "unknown computation `D+2`"))
+        );
+        assert_eq!(
+            assemble_source("D=(A+1)&0xBEBF"),
+            Err(Diagnostic::new(1, "unknown computation `(A+1)&0xBEBF`"))
+        );
+        assert_eq!(
+            assemble_source("DD=1"),
+            Err(Diagnostic::new(1, "invalid destination `DD`"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shifts() -> TestResult {
+        let options = Options {
+            isa: Isa::extended(),
+            ..Options::default()
+        };
+
+        assert_eq!(
+            assemble(Parser::from_source("D=D<<\nAM=M >>;JEQ\nD=D+1"), &options)?,
+            ["1010000110010000", "1011000000101010", "1110011111010000"]
+        );
+        assert_eq!(
+            assemble_source("D=D<<"),
+            Err(Diagnostic::new(
+                1,
+                "`D<<` is a shift of the extended ISA, it needs `--isa extended`"
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_isa() -> TestResult {
+        let options = Options {
+            isa: Isa::load(Path::new("test_files/Mul.toml"))?,
+            ..Options::default()
+        };
+
+        assert_eq!(
+            assemble(Parser::from_source("D=D*A\nMD=D * M;JMP\nD=1+D"), &options)?,
+            ["1000000000010000", "1001000000011111", "1110011111010000"]
+        );
+        assert_eq!(
+            assemble_source("D=D*A"),
+            Err(Diagnostic::new(1, "unknown computation `D*A`"))
+        );
+        assert_eq!(
+            assemble_source("D;JXX"),
+            Err(Diagnostic::new(1, "invalid jump `JXX`"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_target() -> TestResult {
+        let mut options = Options {
+            target: Target::bare(),
+            ..Options::default()
+        };
+        options.target.load(Path::new("test_files/Devices.toml"))?;
+
+        assert_eq!(
+            assemble(Parser::from_source("@LEDS\n@x\n@R0\n@y"), &options)?,
+            [
+                "0110000000000001",
+                "0000000000100000",
+                "0000000000100001",
+                "0000000000100010"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_variable_overflow() -> TestResult {
+        let mut options = Options::default();
+        options
+            .target
+            .update("[symbols]\nSTATUS = 17\n[variables]\nfirst = 0x3FFD")?;
+
+        let assembled = assemble_program(Parser::from_source("@a\n@b\n@a\n@R2"), &options)?;
+        assert_eq!(
+            assembled.variables,
+            [
+                ("a".to_string(), 0x3ffd..=0x3ffd),
+                ("b".to_string(), 0x3ffe..=0x3ffe)
+            ]
+        );
+        assert_eq!(
+            assembled.report(&options.target),
+            "ROM: 4 of 32768 words used
+RAM: 2 of 3 words used by variables, from 16381 to 16383, at 16381 to 16382
+"
+        );
+        assert_eq!(
+            assemble(Parser::from_source("@a\n@b\n@c\n@d"), &options),
+            Err(Diagnostic::new(
+                4,
+                "no room for variable `d`: the addresses from 16381 to 16383 are all used, \
+                 and 16384 is reserved for `SCREEN`"
+            ))
+        );
+
+        // variables go around the addresses of predefined symbols
+        options.target.update("[variables]\nfirst = 16")?;
+        assert_eq!(
+            assemble(Parser::from_source("@a\n@b"), &options)?,
+            ["0000000000010000", "0000000000010010"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_declared_variables() -> TestResult {
+        let source = "\
+.equ SIZE 4
+   @count
+.var buffer, SIZE
+   @buffer+1
+.var count
+   @b";
+        let addresses = |options: &Options| -> Result<Vec<_>, Diagnostic> {
+            Ok(assemble(Parser::from_source(source), options)?
+                .iter()
+                .map(|word| usize::from_str_radix(word, 2).unwrap())
+                .collect())
+        };
+
+        // count, buffer (4 words), b
+        assert_eq!(addresses(&Options::default())?, [16, 18, 21]);
+        let options = Options {
+            alloc_order: AllocOrder::Declaration,
+            ..Options::default()
+        };
+        // buffer, count, b
+        assert_eq!(addresses(&options)?, [20, 17, 21]);
+        let options = Options {
+            alloc_order: AllocOrder::Alphabetical,
+            ..Options::default()
+        };
+        // b, buffer, count
+        assert_eq!(addresses(&options)?, [21, 18, 16]);
+        let options = Options {
+            strict: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            assemble(Parser::from_source(source), &options),
+            Err(Diagnostic::new(
+                6,
+                "undefined symbol `b`, and `--strict` needs variables declared with `.var`"
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_declared_variable_errors() {
+        assert_eq!(
+            assemble_source(".var x\n.var x, 2"),
+            Err(Diagnostic::new(
+                2,
+                "variable `x` is already declared on line 1"
+            ))
+        );
+        assert_eq!(
+            assemble_source(".var LOOP\n(LOOP)"),
+            Err(Diagnostic::new(1, "`LOOP` is already defined on line 2"))
+        );
+        assert_eq!(
+            assemble_source(".var SCREEN"),
+            Err(Diagnostic::new(
+                1,
+                "`SCREEN` is a predefined symbol and can't be redefined"
+            ))
+        );
+        assert_eq!(
+            assemble_source(".var x, 0"),
+            Err(Diagnostic::new(
+                1,
+                "variable `x` has 0 words, but it must have from 1 to 32768"
+            ))
+        );
+        assert_eq!(
+            assemble_source(".var 2x"),
+            Err(Diagnostic::new(1, "variable `2x` can't start with a digit"))
+        );
+        assert_eq!(
+            assemble_source(".var big, 20000"),
+            Err(Diagnostic::new(
+                1,
+                "no room for variable `big`: the addresses from 16 to 16383 are all used, \
+                 and 16384 is reserved for `SCREEN`"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_duplicate_labels() -> TestResult {
+        let source = "(LOOP)\n@LOOP\n(LOOP)\n@1\n(R3)\n@R3";
+        assert_eq!(
+            assemble_source(source),
+            Err(Diagnostic::new(
+                3,
+                "label `LOOP` is already defined on line 1"
+            ))
+        );
+        assert_eq!(
+            assemble_source("(R3)"),
+            Err(Diagnostic::new(
+                1,
+                "label `R3` has the name of a predefined symbol"
+            ))
+        );
+
+        let options = Options {
+            legacy_labels: true,
+            ..Options::default()
+        };
+        let assembled = assemble_program(Parser::from_source(source), &options)?;
+        assert_eq!(
+            assembled.words,
+            [
+                format!("{:016b}", 0),
+                format!("{:016b}", 1),
+                format!("{:016b}", 3)
+            ]
+        );
+        assert_eq!(
+            assembled.warnings,
+            [
+                Diagnostic::new(3, "label `LOOP` is already defined on line 1, ignored"),
+                Diagnostic::new(5, "label `R3` has the name of a predefined symbol, ignored"),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_trailing_comment() -> TestResult {
+        for end in ["\n", "\n// done\n", "\n\n   \n"] {
+            let source = format!(".var y\n.equ X 1\n(END)\n.word X{end}");
+            assert_eq!(
+                assemble_source(&source)?,
+                [format!("{:016b}", 1)],
+                "{end:?}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_label_range() {
+        let source = "0\n".repeat(MAX_A_VALUE + 1) + "(END)\n@END\n";
+
+        assert_eq!(
+            assemble_source(&source),
+            Err(Diagnostic::new(
+                MAX_A_VALUE + 2,
+                "label `END` is at address 32768, past the end of the 32768 word ROM"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_invalid_literal() {
+        assert_eq!(
+            assemble_source("@1\n\n@0xZZ"),
+            Err(Diagnostic::new(3, "invalid number `0xZZ`"))
+        );
+    }
+}
@@ -1,29 +1,124 @@
+use crate::{
+    expr::{BinaryOp, Expr},
+    number::parse_number,
+};
+
 pub struct Code;
 
+/// Computations the ALU can do besides the documented ones, with the
+/// `zx nx zy ny f no` bits that compute them. Those using `A` can use `M`
+/// instead, with the `a` bit set.
+const UNDOCUMENTED_COMPS: [(&str, &str); 14] = [
+    ("!(D&A)", "000001"),
+    ("-D-A-1", "000011"),
+    ("D&!A", "000100"),
+    ("!D|A", "000101"),
+    ("D-A-1", "000110"),
+    ("!D&A", "010000"),
+    ("D|!A", "010001"),
+    ("A-D-1", "010010"),
+    ("!(D|A)", "010100"),
+    ("-D-A-2", "010110"),
+    ("D+A+1", "010111"),
+    ("-D-2", "011110"),
+    ("-A-2", "110110"),
+    ("-2", "111110"),
+];
+
+/// The undocumented computations with their 7 bit comp field, `M` variants
+/// included.
+fn undocumented_comps() -> impl Iterator<Item = (String, String)> {
+    UNDOCUMENTED_COMPS.iter().flat_map(|&(mnemonic, bits)| {
+        let with_m = mnemonic
+            .contains('A')
+            .then(|| (mnemonic.replace('A', "M"), format!("1{bits}")));
+        [(mnemonic.to_string(), format!("0{bits}"))]
+            .into_iter()
+            .chain(with_m)
+    })
+}
+
+/// Values of `D` and `A` (or `M`) that tell any two computations apart.
+const SAMPLES: [(i64, i64); 8] = [
+    (0, 0),
+    (1, 0),
+    (0, 1),
+    (0xffff, 1),
+    (1234, 5678),
+    (0x8000, 0x7fff),
+    (0x5555, 0xaaaa),
+    (0x0ff0, 0x3c3c),
+];
+
+/// Evaluate the computation `expr` on 16 bit words, for each of the
+/// [`SAMPLES`]. Also return whether it uses `M` rather than `A`, and `None`
+/// if it uses anything but `D`, `A` or `M`, or both `A` and `M`.
+fn alu_results(expr: &Expr) -> Option<(Vec<i64>, bool)> {
+    let (mut uses_a, mut uses_m) = (false, false);
+    let results = SAMPLES
+        .iter()
+        .map(|&(d, y)| {
+            expr.eval(&mut |register| match register {
+                "D" => Ok(d),
+                "A" => {
+                    uses_a = true;
+                    Ok(y)
+                }
+                "M" => {
+                    uses_m = true;
+                    Ok(y)
+                }
+                _ => Err(String::new()),
+            })
+            .map(|value| value & 0xffff)
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    (!(uses_a && uses_m)).then_some((results, uses_m))
+}
+
+/// Whether `expr` is made of what the ALU computations are: `D`, `A` or `M`,
+/// the numbers 0, 1 and 2 (negated or not, like in `-D-2`), and `+`, `-`,
+/// `&`, `|` and `!`. Anything else, like `*`, `>>` or `0xBEBF`, could match a
+/// computation on the [`SAMPLES`] without being one, e.g. `D*1` or
+/// `(A+1)&0xBEBF`.
+fn alu_ops_only(expr: &Expr) -> bool {
+    match expr {
+        Expr::Number(n) => (0..=2).contains(n),
+        Expr::Symbol(_) => true,
+        Expr::Unary(_, e) => alu_ops_only(e),
+        Expr::Binary(op, l, r) => {
+            matches!(
+                op,
+                BinaryOp::Add | BinaryOp::Sub | BinaryOp::And | BinaryOp::Or
+            ) && alu_ops_only(l)
+                && alu_ops_only(r)
+        }
+    }
+}
+
 impl Code {
-    pub fn dest(ins: &str) -> String {
-        match ins {
-            "" => "000".to_string(),
-            "M" => "001".to_string(),
-            "D" => "010".to_string(),
-            "DM" => "011".to_string(),
-            "MD" => "011".to_string(),
-            "A" => "100".to_string(),
-            "AM" => "101".to_string(),
-            "MA" => "101".to_string(),
-            "AD" => "110".to_string(),
-            "DA" => "110".to_string(),
-            "ADM" => "111".to_string(),
-            "AMD" => "111".to_string(),
-            "DAM" => "111".to_string(),
-            "DMA" => "111".to_string(),
-            "MAD" => "111".to_string(),
-            "MDA" => "111".to_string(),
-            _ => panic!("unknow dest"),
+    /// The dest field of registers `A`, `D` and `M` in any order, each at
+    /// most once; whitespace is ignored.
+    pub fn dest(ins: &str) -> Option<String> {
+        let mut bits = 0;
+        for register in ins.chars().filter(|c| !c.is_whitespace()) {
+            let bit = match register {
+                'A' => 0b100,
+                'D' => 0b010,
+                'M' => 0b001,
+                _ => return None,
+            };
+            if bits & bit != 0 {
+                return None;
+            }
+            bits |= bit;
         }
+        Some(format!("{bits:03b}"))
     }
-    pub fn comp(ins: &str) -> String {
-        match ins {
+    /// The comp field of a documented computation, `None` for anything else.
+    pub fn comp(ins: &str) -> Option<String> {
+        let bin = match ins {
             "0" => "0101010".to_string(),
             "1" => "0111111".to_string(),
             "-1" => "0111010".to_string(),
@@ -31,6 +126,8 @@ impl Code {
             "A" => "0110000".to_string(),
             "!D" => "0001101".to_string(),
             "!A" => "0110001".to_string(),
+            "-D" => "0001111".to_string(),
+            "-A" => "0110011".to_string(),
             "D+1" => "0011111".to_string(),
             "A+1" => "0110111".to_string(),
             "D-1" => "0001110".to_string(),
@@ -52,22 +149,139 @@ impl Code {
             "M-D" => "1000111".to_string(),
             "D&M" => "1000000".to_string(),
             "D|M" => "1010101".to_string(),
-            _ => panic!("unknow comp"),
+            _ => return None,
+        };
+        Some(bin)
+    }
+
+    /// The comp field of any ALU computation: a documented or undocumented
+    /// one, or a raw `comp(0b0000111)`.
+    pub fn full_comp(ins: &str) -> Option<String> {
+        if let Some(raw) = ins
+            .strip_prefix("comp(")
+            .and_then(|ins| ins.strip_suffix(')'))
+        {
+            return match parse_number(raw) {
+                Some(Ok(bits)) if bits < 1 << 7 => Some(format!("{bits:07b}")),
+                _ => None,
+            };
         }
+        Self::comp(ins).or_else(|| {
+            undocumented_comps()
+                .find(|(mnemonic, _)| mnemonic == ins)
+                .map(|(_, bits)| bits)
+        })
     }
 
-    pub fn jump(ins: &str) -> String {
-        match ins {
-            "" => "000".to_string(),
-            "JGT" => "001".to_string(),
-            "JEQ" => "010".to_string(),
-            "JGE" => "011".to_string(),
-            "JLT" => "100".to_string(),
-            "JNE" => "101".to_string(),
-            "JLE" => "110".to_string(),
-            "JMP" => "111".to_string(),
-            _ => panic!("unknow jump"),
+    /// Find the mnemonic of a computation written in any equivalent way,
+    /// like `1+D` for `D+1` or `M | D` for `D|M`, among the documented ones
+    /// (and the undocumented ones with `full_alu`).
+    ///
+    /// The computation is evaluated like an expression for sample values of
+    /// `D` and `A` (or `M`), and matched with the one that gives the same
+    /// results.
+    pub fn normalize_comp(ins: &str, full_alu: bool) -> Option<String> {
+        let mnemonic = |bin: &str| match full_alu {
+            true => Some(Self::full_comp_mnemonic(bin)).filter(|m| !m.starts_with("comp(")),
+            false => Self::comp_mnemonic(bin),
+        };
+        let canonical = match full_alu {
+            true => Self::full_comp(ins),
+            false => Self::comp(ins),
+        };
+        if let Some(bin) = canonical {
+            return Some(mnemonic(&bin).unwrap_or_else(|| ins.to_string()));
         }
+
+        let expr = Expr::parse(ins).ok().filter(alu_ops_only)?;
+        let (results, uses_m) = alu_results(&expr)?;
+        (0..1 << 7)
+            .map(|bits| format!("{bits:07b}"))
+            // the `a` bit selects `M` instead of `A`
+            .filter(|bin| bin.starts_with('1') == uses_m)
+            .filter_map(|bin| mnemonic(&bin))
+            .find(|mnemonic| {
+                let expr = Expr::parse(mnemonic).unwrap();
+                alu_results(&expr).map(|(r, _)| r).as_ref() == Some(&results)
+            })
+    }
+
+    pub fn dest_mnemonic(bin: &str) -> Option<String> {
+        let mnemonic = match bin {
+            "000" => "",
+            "001" => "M",
+            "010" => "D",
+            "011" => "MD",
+            "100" => "A",
+            "101" => "AM",
+            "110" => "AD",
+            "111" => "AMD",
+            _ => return None,
+        };
+        Some(mnemonic.to_string())
+    }
+
+    pub fn comp_mnemonic(bin: &str) -> Option<String> {
+        let mnemonic = match bin {
+            "0101010" => "0",
+            "0111111" => "1",
+            "0111010" => "-1",
+            "0001100" => "D",
+            "0110000" => "A",
+            "0001101" => "!D",
+            "0110001" => "!A",
+            "0001111" => "-D",
+            "0110011" => "-A",
+            "0011111" => "D+1",
+            "0110111" => "A+1",
+            "0001110" => "D-1",
+            "0110010" => "A-1",
+            "0000010" => "D+A",
+            "0010011" => "D-A",
+            "0000111" => "A-D",
+            "0000000" => "D&A",
+            "0010101" => "D|A",
+            "1110000" => "M",
+            "1110001" => "!M",
+            "1110011" => "-M",
+            "1110111" => "M+1",
+            "1110010" => "M-1",
+            "1000010" => "D+M",
+            "1010011" => "D-M",
+            "1000111" => "M-D",
+            "1000000" => "D&M",
+            "1010101" => "D|M",
+            _ => return None,
+        };
+        Some(mnemonic.to_string())
+    }
+
+    /// The mnemonic of any 7 bit comp field: the documented or undocumented
+    /// one, or `comp(0b...)` for encodings that compute the same thing as
+    /// another one.
+    pub fn full_comp_mnemonic(bin: &str) -> String {
+        Self::comp_mnemonic(bin)
+            .or_else(|| {
+                undocumented_comps()
+                    .find(|(_, bits)| bits == bin)
+                    .map(|(mnemonic, _)| mnemonic)
+            })
+            .unwrap_or_else(|| format!("comp(0b{bin})"))
+    }
+
+    pub fn jump_mnemonic(bin: &str) -> Option<String> {
+        let mnemonic = match bin {
+            "000" => "",
+            "001" => "JGT",
+            "010" => "JEQ",
+            "011" => "JGE",
+            "100" => "JLT",
+            "101" => "JNE",
+            "110" => "JLE",
+            "111" => "JMP",
+            _ => return None,
+        };
+        Some(mnemonic.to_string())
     }
 }
 
@@ -77,65 +291,144 @@ mod tests {
 
     #[test]
     fn test_dest() {
-        assert_eq!(Code::dest(""), "000");
-        assert_eq!(Code::dest("M"), "001");
-        assert_eq!(Code::dest("D"), "010");
-        assert_eq!(Code::dest("DM"), "011");
-        assert_eq!(Code::dest("MD"), "011");
-        assert_eq!(Code::dest("A"), "100");
-        assert_eq!(Code::dest("AM"), "101");
-        assert_eq!(Code::dest("MA"), "101");
-        assert_eq!(Code::dest("AD"), "110");
-        assert_eq!(Code::dest("DA"), "110");
-        assert_eq!(Code::dest("ADM"), "111");
-        assert_eq!(Code::dest("AMD"), "111");
-        assert_eq!(Code::dest("DAM"), "111");
-        assert_eq!(Code::dest("DMA"), "111");
-        assert_eq!(Code::dest("MAD"), "111");
-        assert_eq!(Code::dest("MDA"), "111");
+        assert_eq!(Code::dest("").as_deref(), Some("000"));
+        assert_eq!(Code::dest("M").as_deref(), Some("001"));
+        assert_eq!(Code::dest("D").as_deref(), Some("010"));
+        assert_eq!(Code::dest("DM").as_deref(), Some("011"));
+        assert_eq!(Code::dest("MD").as_deref(), Some("011"));
+        assert_eq!(Code::dest("A").as_deref(), Some("100"));
+        assert_eq!(Code::dest("AM").as_deref(), Some("101"));
+        assert_eq!(Code::dest("MA").as_deref(), Some("101"));
+        assert_eq!(Code::dest("AD").as_deref(), Some("110"));
+        assert_eq!(Code::dest("DA").as_deref(), Some("110"));
+        assert_eq!(Code::dest("ADM").as_deref(), Some("111"));
+        assert_eq!(Code::dest("AMD").as_deref(), Some("111"));
+        assert_eq!(Code::dest("DAM").as_deref(), Some("111"));
+        assert_eq!(Code::dest("DMA").as_deref(), Some("111"));
+        assert_eq!(Code::dest("MAD").as_deref(), Some("111"));
+        assert_eq!(Code::dest("MDA").as_deref(), Some("111"));
+    }
+
+    #[test]
+    fn test_invalid_dest() {
+        assert_eq!(Code::dest(" A M "), Some("101".to_string()));
+        assert_eq!(Code::dest("AA"), None);
+        assert_eq!(Code::dest("X"), None);
+    }
+
+    #[test]
+    fn test_normalize_comp() {
+        let normalize = |ins| Code::normalize_comp(ins, false);
+        assert_eq!(normalize("D+1").as_deref(), Some("D+1"));
+        assert_eq!(normalize("A+D").as_deref(), Some("D+A"));
+        assert_eq!(normalize("1+D").as_deref(), Some("D+1"));
+        assert_eq!(normalize("M + 1").as_deref(), Some("M+1"));
+        assert_eq!(normalize("A&D").as_deref(), Some("D&A"));
+        assert_eq!(normalize("M|D").as_deref(), Some("D|M"));
+        assert_eq!(normalize("-1+M").as_deref(), Some("M-1"));
+        assert_eq!(normalize("~D").as_deref(), Some("!D"));
+        assert_eq!(normalize("0-A").as_deref(), Some("-A"));
+        assert_eq!(normalize("A-A").as_deref(), Some("0"));
+        assert_eq!(normalize("D+A+1"), None);
+        assert_eq!(normalize("D+2"), None);
+        assert_eq!(normalize("A+M"), None);
+        assert_eq!(normalize("D+X"), None);
+        assert_eq!(normalize("D+"), None);
+        assert_eq!(normalize("(D<<1)>>1"), None);
+        assert_eq!(normalize("D*1"), None);
+        assert_eq!(normalize("D/1"), None);
+        assert_eq!(normalize("D^0"), None);
+        assert_eq!(normalize("(D==D)-1"), None);
+        assert_eq!(normalize("(A+1)&0xBEBF"), None);
+        assert_eq!(Code::normalize_comp("-2-M", true).as_deref(), Some("-M-2"));
+        assert_eq!(
+            Code::normalize_comp("1+A+D", true).as_deref(),
+            Some("D+A+1")
+        );
+        assert_eq!(Code::normalize_comp("!M|D", true).as_deref(), Some("D|!M"));
+        assert_eq!(
+            Code::normalize_comp("comp(0b0001010)", true).as_deref(),
+            Some("comp(0b0001010)")
+        );
+    }
+
+    #[test]
+    fn test_samples_tell_computations_apart() {
+        let mnemonics = (0..1 << 7)
+            .map(|bits| Code::full_comp_mnemonic(&format!("{bits:07b}")))
+            .filter(|mnemonic| !mnemonic.starts_with("comp("))
+            .collect::<Vec<_>>();
+        let results = mnemonics
+            .iter()
+            .map(|mnemonic| alu_results(&Expr::parse(mnemonic).unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        for (i, a) in results.iter().enumerate() {
+            for (j, b) in results.iter().enumerate().skip(i + 1) {
+                assert_ne!(a, b, "{} and {}", mnemonics[i], mnemonics[j]);
+            }
+        }
     }
 
     #[test]
     fn test_comp() {
-        assert_eq!(Code::comp("0"), "0101010");
-        assert_eq!(Code::comp("1"), "0111111");
-        assert_eq!(Code::comp("-1"), "0111010");
-        assert_eq!(Code::comp("D"), "0001100");
-        assert_eq!(Code::comp("A"), "0110000");
-        assert_eq!(Code::comp("!D"), "0001101");
-        assert_eq!(Code::comp("!A"), "0110001");
-        assert_eq!(Code::comp("D+1"), "0011111");
-        assert_eq!(Code::comp("A+1"), "0110111");
-        assert_eq!(Code::comp("D-1"), "0001110");
-        assert_eq!(Code::comp("A-1"), "0110010");
-        assert_eq!(Code::comp("D+A"), "0000010");
-        assert_eq!(Code::comp("A+D"), "0000010");
-        assert_eq!(Code::comp("D-A"), "0010011");
-        assert_eq!(Code::comp("A-D"), "0000111");
-        assert_eq!(Code::comp("D&A"), "0000000");
-        assert_eq!(Code::comp("D|A"), "0010101");
-        assert_eq!(Code::comp("M"), "1110000");
-        assert_eq!(Code::comp("!M"), "1110001");
-        assert_eq!(Code::comp("-M"), "1110011");
-        assert_eq!(Code::comp("M+1"), "1110111");
-        assert_eq!(Code::comp("M-1"), "1110010");
-        assert_eq!(Code::comp("D+M"), "1000010");
-        assert_eq!(Code::comp("M+D"), "1000010");
-        assert_eq!(Code::comp("D-M"), "1010011");
-        assert_eq!(Code::comp("M-D"), "1000111");
-        assert_eq!(Code::comp("D&M"), "1000000");
-        assert_eq!(Code::comp("D|M"), "1010101");
+        assert_eq!(Code::comp("0").as_deref(), Some("0101010"));
+        assert_eq!(Code::comp("1").as_deref(), Some("0111111"));
+        assert_eq!(Code::comp("-1").as_deref(), Some("0111010"));
+        assert_eq!(Code::comp("D").as_deref(), Some("0001100"));
+        assert_eq!(Code::comp("A").as_deref(), Some("0110000"));
+        assert_eq!(Code::comp("!D").as_deref(), Some("0001101"));
+        assert_eq!(Code::comp("!A").as_deref(), Some("0110001"));
+        assert_eq!(Code::comp("-D").as_deref(), Some("0001111"));
+        assert_eq!(Code::comp("-A").as_deref(), Some("0110011"));
+        assert_eq!(Code::comp("D+1").as_deref(), Some("0011111"));
+        assert_eq!(Code::comp("A+1").as_deref(), Some("0110111"));
+        assert_eq!(Code::comp("D-1").as_deref(), Some("0001110"));
+        assert_eq!(Code::comp("A-1").as_deref(), Some("0110010"));
+        assert_eq!(Code::comp("D+A").as_deref(), Some("0000010"));
+        assert_eq!(Code::comp("A+D").as_deref(), Some("0000010"));
+        assert_eq!(Code::comp("D-A").as_deref(), Some("0010011"));
+        assert_eq!(Code::comp("A-D").as_deref(), Some("0000111"));
+        assert_eq!(Code::comp("D&A").as_deref(), Some("0000000"));
+        assert_eq!(Code::comp("D|A").as_deref(), Some("0010101"));
+        assert_eq!(Code::comp("M").as_deref(), Some("1110000"));
+        assert_eq!(Code::comp("!M").as_deref(), Some("1110001"));
+        assert_eq!(Code::comp("-M").as_deref(), Some("1110011"));
+        assert_eq!(Code::comp("M+1").as_deref(), Some("1110111"));
+        assert_eq!(Code::comp("M-1").as_deref(), Some("1110010"));
+        assert_eq!(Code::comp("D+M").as_deref(), Some("1000010"));
+        assert_eq!(Code::comp("M+D").as_deref(), Some("1000010"));
+        assert_eq!(Code::comp("D-M").as_deref(), Some("1010011"));
+        assert_eq!(Code::comp("M-D").as_deref(), Some("1000111"));
+        assert_eq!(Code::comp("D&M").as_deref(), Some("1000000"));
+        assert_eq!(Code::comp("D|M").as_deref(), Some("1010101"));
     }
 
     #[test]
-    fn test_jump() {
-        assert_eq!(Code::jump(""), "000");
-        assert_eq!(Code::jump("JGT"), "001");
-        assert_eq!(Code::jump("JEQ"), "010");
-        assert_eq!(Code::jump("JGE"), "011");
-        assert_eq!(Code::jump("JLT"), "100");
-        assert_eq!(Code::jump("JNE"), "101");
-        assert_eq!(Code::jump("JLE"), "110");
-        assert_eq!(Code::jump("JMP"), "111");
+    fn test_full_comp() {
+        assert_eq!(Code::comp("D+A+1"), None);
+        assert_eq!(Code::full_comp("D+A"), Some("0000010".to_string()));
+        assert_eq!(Code::full_comp("D+A+1"), Some("0010111".to_string()));
+        assert_eq!(Code::full_comp("D|!M"), Some("1010001".to_string()));
+        assert_eq!(Code::full_comp("-2"), Some("0111110".to_string()));
+        assert_eq!(Code::full_comp("-M-2"), Some("1110110".to_string()));
+        assert_eq!(
+            Code::full_comp("comp(0b0001010)"),
+            Some("0001010".to_string())
+        );
+        assert_eq!(Code::full_comp("comp(0x7F)"), Some("1111111".to_string()));
+        assert_eq!(Code::full_comp("comp(0b10000000)"), None);
+        assert_eq!(Code::full_comp("comp()"), None);
+        assert_eq!(Code::full_comp("D*A"), None);
+    }
+
+    #[test]
+    fn test_full_comp_round_trip() {
+        for bits in 0..1 << 7 {
+            let bin = format!("{bits:07b}");
+            let mnemonic = Code::full_comp_mnemonic(&bin);
+            assert_eq!(Code::full_comp(&mnemonic), Some(bin), "{mnemonic}");
+        }
+        assert_eq!(Code::full_comp_mnemonic("0010111"), "D+A+1");
+        assert_eq!(Code::full_comp_mnemonic("0001010"), "comp(0b0001010)");
     }
 }
@@ -0,0 +1,99 @@
+use std::{error::Error, fmt, path::PathBuf, rc::Rc};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    /// For a file read by `.include`, the `.include` line.
+    pub included_from: Option<Location>,
+}
+
+/// Where a source line comes from.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Location {
+    /// `None` for source that isn't read from a file.
+    pub file: Option<Rc<SourceFile>>,
+    pub line: usize,
+    /// For a line of a macro body, the macro call it was expanded from (or
+    /// the pseudo-instruction).
+    pub expansion: Option<Rc<Expansion>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ExpansionKind {
+    Macro,
+    PseudoOp,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Expansion {
+    pub kind: ExpansionKind,
+    /// The macro name, or the whole pseudo-instruction like `goto LOOP`.
+    pub name: String,
+    pub call_site: Location,
+}
+
+impl From<usize> for Location {
+    fn from(line: usize) -> Self {
+        Self {
+            file: None,
+            line,
+            expansion: None,
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file.path.display(), self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/// An error found in the assembly source, with the line it was found on.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Diagnostic {
+    pub location: Location,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(location: impl Into<Location>, message: impl Into<String>) -> Self {
+        Self {
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)?;
+        let mut location = &self.location;
+        while let Some(e) = &location.expansion {
+            match e.kind {
+                ExpansionKind::Macro => {
+                    write!(f, "\n  in macro `{}` called on {}", e.name, e.call_site)?
+                }
+                ExpansionKind::PseudoOp => write!(
+                    f,
+                    "\n  in pseudo-instruction `{}` on {}",
+                    e.name, e.call_site
+                )?,
+            }
+            location = &e.call_site;
+        }
+        while let Some(include) = location
+            .file
+            .as_ref()
+            .and_then(|file| file.included_from.as_ref())
+        {
+            write!(f, "\n  included from {include}")?;
+            location = include;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Diagnostic {}
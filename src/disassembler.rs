@@ -0,0 +1,370 @@
+use std::collections::HashSet;
+
+use crate::{code::Code, options::Options, symbol_table::SymbolTable};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Word {
+    A(usize),
+    C {
+        dest: String,
+        comp: String,
+        jump: String,
+    },
+    /// A word that isn't a valid instruction, kept as `.word`.
+    Raw(u16),
+}
+
+/// How the value loaded by an A-instruction is used by the instruction
+/// right after it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Usage {
+    Jump,
+    Memory,
+    Data,
+}
+
+impl Word {
+    /// Decode a line of 16 binary digits, `None` if it is something else.
+    /// C-instructions are decoded with the tables of `--isa`, and with
+    /// `--full-alu` every comp field of the `111` prefix is a computation.
+    fn decode(line: &str, options: &Options) -> Option<Self> {
+        if line.len() != 16 || !line.chars().all(|c| c == '0' || c == '1') {
+            return None;
+        }
+        let value = u16::from_str_radix(line, 2).ok()?;
+        if line.starts_with('0') {
+            return Some(Self::A(value as usize));
+        }
+        let isa = &options.isa;
+        let c = || {
+            Some(Self::C {
+                comp: match isa.comp_mnemonic(&line[..10]) {
+                    Some(mnemonic) => mnemonic.to_string(),
+                    None if options.full_alu && line.starts_with("111") => {
+                        Code::full_comp_mnemonic(&line[3..10])
+                    }
+                    None => return None,
+                },
+                dest: isa.dest_mnemonic(&line[10..13])?.to_string(),
+                jump: isa.jump_mnemonic(&line[13..16])?.to_string(),
+            })
+        };
+        Some(c().unwrap_or(Self::Raw(value)))
+    }
+}
+
+/// Turn the lines of a `.hack` file back into assembly code.
+///
+/// `@n` followed by a jump becomes a reference to a generated `(L_n)`
+/// label, addresses with a predefined symbol in `symbol_table` use that
+/// name, and RAM addresses only used through `M` become `var_n` variables.
+/// Words that aren't instructions become `.word`; with `--full-alu`,
+/// undocumented computations get their mnemonic instead. Reassembling the
+/// result gives back the same words.
+pub fn disassemble(
+    lines: &[&str],
+    symbol_table: &SymbolTable,
+    options: &Options,
+) -> Result<String, String> {
+    let words = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            Word::decode(line.trim(), options)
+                .ok_or_else(|| format!("line {}: `{}` is not a valid instruction", i + 1, line))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let usage = |i: usize| match words.get(i + 1) {
+        Some(Word::C { jump, .. }) if !jump.is_empty() => Usage::Jump,
+        Some(Word::C { dest, comp, .. }) if dest.contains('M') || comp.contains('M') => {
+            Usage::Memory
+        }
+        _ => Usage::Data,
+    };
+    let loads = words
+        .iter()
+        .enumerate()
+        .filter_map(|(i, word)| match word {
+            Word::A(value) => Some((*value, usage(i))),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    // a label can only be placed at an existing address or right after the end
+    let labels = loads
+        .iter()
+        .filter(|&&(value, usage)| usage == Usage::Jump && value <= words.len())
+        .map(|&(value, _)| value)
+        .collect::<HashSet<_>>();
+
+    // variables are allocated in order of first use, so only addresses that
+    // the assembler would hand out again in the same order can get a name,
+    // and only if they are never loaded as data or a jump target
+    let not_memory = loads
+        .iter()
+        .filter(|&&(_, usage)| usage != Usage::Memory)
+        .map(|&(value, _)| value)
+        .collect::<HashSet<_>>();
+    let first_var = symbol_table.alloc_pos;
+    let mut vars = HashSet::new();
+    let mut seen = HashSet::new();
+    let mut alloc_pos = first_var;
+    for &(value, usage) in &loads {
+        if usage != Usage::Memory
+            || value < first_var
+            || not_memory.contains(&value)
+            || symbol_table.find_symbol(value).is_some()
+            || !seen.insert(value)
+        {
+            continue;
+        }
+        if value == alloc_pos {
+            vars.insert(value);
+            alloc_pos += 1;
+        }
+    }
+
+    let mut output = String::new();
+    let mut loads = loads.into_iter();
+    for (address, word) in words.iter().enumerate() {
+        if labels.contains(&address) {
+            output += &format!("(L_{address})\n");
+        }
+        let ins = match word {
+            Word::A(_) => {
+                let (value, usage) = loads.next().unwrap();
+                let symbol = match usage {
+                    Usage::Jump if labels.contains(&value) => format!("L_{value}"),
+                    Usage::Memory if vars.contains(&value) => format!("var_{value}"),
+                    Usage::Memory => symbol_table
+                        .find_symbol(value)
+                        .map_or_else(|| value.to_string(), String::from),
+                    // small constants are not addresses, but device base
+                    // addresses like `SCREEN` usually are
+                    Usage::Data if value >= first_var => symbol_table
+                        .find_symbol(value)
+                        .map_or_else(|| value.to_string(), String::from),
+                    _ => value.to_string(),
+                };
+                format!("@{symbol}")
+            }
+            Word::C { dest, comp, jump } => {
+                let mut ins = String::new();
+                if !dest.is_empty() {
+                    ins += &format!("{dest}=");
+                }
+                ins += comp;
+                if !jump.is_empty() {
+                    ins += &format!(";{jump}");
+                }
+                ins
+            }
+            Word::Raw(value) => format!(".word 0x{value:04X}"),
+        };
+        output += &format!("    {ins}\n");
+    }
+    if labels.contains(&words.len()) {
+        output += &format!("(L_{})\n", words.len());
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io, path::Path};
+
+    use super::*;
+    use crate::{assembler::assemble, isa::Isa, options::Options, parser::Parser, target::Target};
+
+    fn read_hack(name: &str) -> io::Result<String> {
+        fs::read_to_string(Path::new("test_files").join(format!("{name}.hack")))
+    }
+
+    #[test]
+    fn test_labels_and_predefined_symbols() -> Result<(), Box<dyn std::error::Error>> {
+        let hack = read_hack("Max")?;
+        let asm = disassemble(
+            &hack.lines().collect::<Vec<_>>(),
+            &SymbolTable::new(&Target::default()),
+            &Options::default(),
+        )?;
+
+        assert!(asm.contains("(L_10)\n"));
+        assert!(asm.contains("    @L_10\n    D;JGT\n"));
+        assert!(asm.contains("(L_14)\n    @L_14\n    0;JMP\n"));
+        assert!(asm.contains("    @LCL\n    D=M\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_variables() -> Result<(), Box<dyn std::error::Error>> {
+        let hack = read_hack("Rect")?;
+        let asm = disassemble(
+            &hack.lines().collect::<Vec<_>>(),
+            &SymbolTable::new(&Target::default()),
+            &Options::default(),
+        )?;
+
+        assert!(asm.contains("    @SCREEN\n    D=A\n"));
+        assert!(asm.contains("    @var_16\n"));
+        assert!(asm.contains("    @var_17\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_variable_out_of_allocation_order() -> Result<(), String> {
+        // RAM[17] is used before RAM[16], so a `var_17` would be allocated at 16
+        let lines = [
+            "0000000000010001",
+            "1111110000010000",
+            "0000000000010000",
+            "1110001100001000",
+        ];
+        let asm = disassemble(
+            &lines,
+            &SymbolTable::new(&Target::default()),
+            &Options::default(),
+        )?;
+
+        assert_eq!(asm, "    @17\n    D=M\n    @var_16\n    M=D\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_variable_used_as_data() -> Result<(), String> {
+        let lines = [
+            "0000000000010000",
+            "1110110000010000",
+            "0000000000010000",
+            "1110001100001000",
+        ];
+        let asm = disassemble(
+            &lines,
+            &SymbolTable::new(&Target::default()),
+            &Options::default(),
+        )?;
+
+        assert_eq!(asm, "    @16\n    D=A\n    @16\n    M=D\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_words() -> Result<(), Box<dyn std::error::Error>> {
+        // a `101` prefix, and an unused comp
+        let lines = ["0000000000010001", "1011110000010000", "1110000001010000"];
+        let asm = disassemble(
+            &lines,
+            &SymbolTable::new(&Target::default()),
+            &Options::default(),
+        )?;
+
+        assert_eq!(
+            asm,
+            "    @17
+    .word 0xBC10
+    .word 0xE050
+"
+        );
+        assert_eq!(
+            assemble(Parser::from_source(&asm), &Options::default())?,
+            lines
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_full_alu() -> Result<(), Box<dyn std::error::Error>> {
+        // D+A+1, and a `D` computed with other bits than the documented ones
+        let lines = ["1110010111010000", "1110001010001000"];
+        let options = Options {
+            full_alu: true,
+            ..Options::default()
+        };
+        let asm = disassemble(&lines, &SymbolTable::new(&Target::default()), &options)?;
+
+        assert_eq!(asm, "    D=D+A+1\n    M=comp(0b0001010)\n");
+        assert_eq!(assemble(Parser::from_source(&asm), &options)?, lines);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shifts() -> Result<(), Box<dyn std::error::Error>> {
+        let lines = ["1010000110010000", "1011000000001111"];
+        let options = Options {
+            isa: Isa::extended(),
+            ..Options::default()
+        };
+        let asm = disassemble(&lines, &SymbolTable::new(&Target::default()), &options)?;
+
+        assert_eq!(asm, "    D=D<<\n    M=M>>;JMP\n");
+        assert_eq!(assemble(Parser::from_source(&asm), &options)?, lines);
+        assert_eq!(
+            disassemble(
+                &lines,
+                &SymbolTable::new(&Target::default()),
+                &Options::default()
+            )?,
+            "    .word 0xA190\n    .word 0xB00F\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_isa() -> Result<(), Box<dyn std::error::Error>> {
+        let lines = ["1000000000010000", "1001000000001000", "1110101010000111"];
+        let options = Options {
+            isa: Isa::load(Path::new("test_files/Mul.toml"))?,
+            ..Options::default()
+        };
+        let asm = disassemble(&lines, &SymbolTable::new(&Target::default()), &options)?;
+
+        assert_eq!(asm, "    D=D*A\n    M=D*M\n    0;JMP\n");
+        assert_eq!(assemble(Parser::from_source(&asm), &options)?, lines);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_line() {
+        let lines = ["0000000000010001", "10011100"];
+
+        assert_eq!(
+            disassemble(
+                &lines,
+                &SymbolTable::new(&Target::default()),
+                &Options::default()
+            ),
+            Err("line 2: `10011100` is not a valid instruction".to_string())
+        );
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        for name in ["Add", "Max", "Rect", "Pong"] {
+            let hack = read_hack(name)?;
+            let lines = hack.lines().collect::<Vec<_>>();
+            let asm = disassemble(
+                &lines,
+                &SymbolTable::new(&Target::default()),
+                &Options::default(),
+            )?;
+
+            assert_eq!(
+                assemble(Parser::from_source(&asm), &Options::default())?,
+                lines,
+                "{name}.hack"
+            );
+        }
+
+        Ok(())
+    }
+}
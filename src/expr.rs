@@ -0,0 +1,389 @@
+use std::{iter::Peekable, str::Chars};
+
+use crate::number::parse_number;
+
+/// An assembly-time constant expression, like `SCREEN+32*ROW` or
+/// `(KBD-1)&0x7FFF`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl BinaryOp {
+    /// Binding power, C-like: `|` < `^` < `&` < `== !=` < `< <= > >=` <
+    /// shifts < `+ -` < `* / %`.
+    fn precedence(self) -> u8 {
+        use BinaryOp::*;
+        match self {
+            Or => 1,
+            Xor => 2,
+            And => 3,
+            Eq | Ne => 4,
+            Lt | Le | Gt | Ge => 5,
+            Shl | Shr => 6,
+            Add | Sub => 7,
+            Mul | Div | Rem => 8,
+        }
+    }
+
+    fn apply(self, l: i64, r: i64) -> Option<i64> {
+        use BinaryOp::*;
+        match self {
+            Add => l.checked_add(r),
+            Sub => l.checked_sub(r),
+            Mul => l.checked_mul(r),
+            Div => l.checked_div(r),
+            Rem => l.checked_rem(r),
+            // `checked_shl` only checks the shift amount, not the bits lost
+            Shl => u32::try_from(r)
+                .ok()
+                .and_then(|r| l.checked_shl(r))
+                .filter(|shifted| shifted >> r == l),
+            Shr => u32::try_from(r).ok().and_then(|r| l.checked_shr(r)),
+            And => Some(l & r),
+            Or => Some(l | r),
+            Xor => Some(l ^ r),
+            // comparisons are 1 if true, 0 if false
+            Eq => Some((l == r) as i64),
+            Ne => Some((l != r) as i64),
+            Lt => Some((l < r) as i64),
+            Le => Some((l <= r) as i64),
+            Gt => Some((l > r) as i64),
+            Ge => Some((l >= r) as i64),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Token {
+    Number(i64),
+    Symbol(String),
+    Unary(UnaryOp),
+    Binary(BinaryOp),
+    // `-` is either negation or subtraction, depending on where it is
+    Minus,
+    LParen,
+    RParen,
+}
+
+/// Characters allowed in a symbol besides letters and digits.
+pub fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$' | ':')
+}
+
+/// Check that `symbol` has only letters, digits, `_`, `.`, `$` and `:`, and
+/// doesn't start with a digit. `kind` names it in the error.
+pub fn check_symbol(kind: &str, symbol: &str) -> Result<(), String> {
+    if symbol.is_empty() {
+        return Err(format!("missing {kind} name"));
+    }
+    if symbol.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err(format!("{kind} `{symbol}` can't start with a digit"));
+    }
+    match symbol.chars().find(|&c| !is_symbol_char(c)) {
+        Some(c) => Err(format!(
+            "{kind} `{symbol}` can't contain `{c}`, only letters, digits, `_`, `.`, `$` and `:`"
+        )),
+        None => Ok(()),
+    }
+}
+
+fn take_while(chars: &mut Peekable<Chars>, first: char, f: impl Fn(char) -> bool) -> String {
+    let mut s = first.to_string();
+    while let Some(&c) = chars.peek() {
+        if !f(c) {
+            break;
+        }
+        s.push(c);
+        chars.next();
+    }
+    s
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            ' ' | '\t' => continue,
+            '\'' => {
+                let literal = [Some(c), chars.next(), chars.next()]
+                    .into_iter()
+                    .flatten()
+                    .collect::<String>();
+                Token::Number(parse_number(&literal).unwrap()? as i64)
+            }
+            c if c.is_ascii_digit() => {
+                let literal = take_while(&mut chars, c, |c| c.is_ascii_alphanumeric() || c == '_');
+                Token::Number(parse_number(&literal).unwrap()? as i64)
+            }
+            c if is_symbol_char(c) => Token::Symbol(take_while(&mut chars, c, is_symbol_char)),
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '-' => Token::Minus,
+            '!' if chars.next_if_eq(&'=').is_some() => Token::Binary(BinaryOp::Ne),
+            '~' | '!' => Token::Unary(UnaryOp::Not),
+            '+' => Token::Binary(BinaryOp::Add),
+            '*' => Token::Binary(BinaryOp::Mul),
+            '/' => Token::Binary(BinaryOp::Div),
+            '%' => Token::Binary(BinaryOp::Rem),
+            '&' => Token::Binary(BinaryOp::And),
+            '|' => Token::Binary(BinaryOp::Or),
+            '^' => Token::Binary(BinaryOp::Xor),
+            '=' if chars.next_if_eq(&'=').is_some() => Token::Binary(BinaryOp::Eq),
+            '<' if chars.next_if_eq(&'<').is_some() => Token::Binary(BinaryOp::Shl),
+            '<' if chars.next_if_eq(&'=').is_some() => Token::Binary(BinaryOp::Le),
+            '<' => Token::Binary(BinaryOp::Lt),
+            '>' if chars.next_if_eq(&'>').is_some() => Token::Binary(BinaryOp::Shr),
+            '>' if chars.next_if_eq(&'=').is_some() => Token::Binary(BinaryOp::Ge),
+            '>' => Token::Binary(BinaryOp::Gt),
+            c => return Err(format!("unexpected `{c}` in expression `{s}`")),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct ExprParser<'a> {
+    source: &'a str,
+    tokens: Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl ExprParser<'_> {
+    fn error(&self, what: &str) -> String {
+        format!("{what} in expression `{}`", self.source)
+    }
+
+    fn peek_binary(&mut self) -> Option<BinaryOp> {
+        match self.tokens.peek()? {
+            Token::Binary(op) => Some(*op),
+            Token::Minus => Some(BinaryOp::Sub),
+            _ => None,
+        }
+    }
+
+    /// Precedence climbing: parse operands joined by operators that bind
+    /// tighter than `min_precedence`.
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.peek_binary() {
+            if op.precedence() <= min_precedence {
+                break;
+            }
+            self.tokens.next();
+            let rhs = self.binary(op.precedence())?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.tokens.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Symbol(s)) => Ok(Expr::Symbol(s)),
+            Some(Token::Minus) => Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.unary()?))),
+            Some(Token::Unary(op)) => Ok(Expr::Unary(op, Box::new(self.unary()?))),
+            Some(Token::LParen) => {
+                let expr = self.binary(0)?;
+                match self.tokens.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err(self.error("missing `)`")),
+                }
+            }
+            Some(Token::RParen) => Err(self.error("unexpected `)`")),
+            Some(Token::Binary(_)) => Err(self.error("missing operand")),
+            None => Err(self.error("missing operand")),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parser = ExprParser {
+            source: s,
+            tokens: tokenize(s)?.into_iter().peekable(),
+        };
+        let expr = parser.binary(0)?;
+        match parser.tokens.next() {
+            None => Ok(expr),
+            Some(Token::RParen) => Err(parser.error("unexpected `)`")),
+            Some(_) => Err(parser.error("missing operator")),
+        }
+    }
+
+    /// Evaluate the expression, getting the value of each symbol from
+    /// `lookup`.
+    pub fn eval(
+        &self,
+        lookup: &mut impl FnMut(&str) -> Result<i64, String>,
+    ) -> Result<i64, String> {
+        match self {
+            Expr::Number(n) => Ok(*n),
+            Expr::Symbol(s) => lookup(s),
+            Expr::Unary(UnaryOp::Neg, e) => e
+                .eval(lookup)?
+                .checked_neg()
+                .ok_or_else(|| format!("overflow in `{self}`")),
+            Expr::Unary(UnaryOp::Not, e) => Ok(!e.eval(lookup)?),
+            Expr::Binary(op, l, r) => {
+                let (l, r) = (l.eval(lookup)?, r.eval(lookup)?);
+                if r == 0 && matches!(op, BinaryOp::Div | BinaryOp::Rem) {
+                    return Err(format!("division by zero in `{self}`"));
+                }
+                op.apply(l, r)
+                    .ok_or_else(|| format!("overflow in `{self}`"))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use BinaryOp::*;
+        match self {
+            Expr::Number(n) => write!(f, "{n}"),
+            Expr::Symbol(s) => write!(f, "{s}"),
+            Expr::Unary(UnaryOp::Neg, e) => write!(f, "-{e}"),
+            Expr::Unary(UnaryOp::Not, e) => write!(f, "~{e}"),
+            Expr::Binary(op, l, r) => {
+                let op = match op {
+                    Add => "+",
+                    Sub => "-",
+                    Mul => "*",
+                    Div => "/",
+                    Rem => "%",
+                    Shl => "<<",
+                    Shr => ">>",
+                    And => "&",
+                    Or => "|",
+                    Xor => "^",
+                    Eq => "==",
+                    Ne => "!=",
+                    Lt => "<",
+                    Le => "<=",
+                    Gt => ">",
+                    Ge => ">=",
+                };
+                write!(f, "({l}{op}{r})")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str) -> Result<i64, String> {
+        Expr::parse(s)?.eval(&mut |symbol| match symbol {
+            "SCREEN" => Ok(16384),
+            "KBD" => Ok(24576),
+            "LOOP" => Ok(10),
+            _ => Err(format!("unknown symbol `{symbol}`")),
+        })
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Expr::parse("42"), Ok(Expr::Number(42)));
+        assert_eq!(
+            Expr::parse("ponggame.0"),
+            Ok(Expr::Symbol("ponggame.0".to_string()))
+        );
+        assert_eq!(
+            Expr::parse("SCREEN + 32").map(|e| e.to_string()),
+            Ok("(SCREEN+32)".to_string())
+        );
+        assert_eq!(
+            Expr::parse("1+2*3-4").map(|e| e.to_string()),
+            Ok("((1+(2*3))-4)".to_string())
+        );
+        assert_eq!(
+            Expr::parse("-(1|2)&~3").map(|e| e.to_string()),
+            Ok("(-(1|2)&~3)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(eval("SCREEN+32"), Ok(16416));
+        assert_eq!(eval("LOOP-1"), Ok(9));
+        assert_eq!(eval("KBD*2/4"), Ok(12288));
+        assert_eq!(eval("10-4-3"), Ok(3));
+        assert_eq!(eval("(SCREEN+32)*2"), Ok(32832));
+        assert_eq!(eval("1<<4|0x0F"), Ok(31));
+        assert_eq!(eval("0xFF&~0x0F^1"), Ok(0xF1));
+        assert_eq!(eval("!0&0x7FFF"), Ok(0x7FFF));
+        assert_eq!(eval("-'A'+'B'"), Ok(1));
+        assert_eq!(eval("'''+' '"), Ok(71));
+        assert_eq!(eval("17%5"), Ok(2));
+        assert_eq!(eval("LOOP == 10"), Ok(1));
+        assert_eq!(eval("LOOP != 10"), Ok(0));
+        assert_eq!(eval("1 < 2 == 2 >= 2"), Ok(1));
+        assert_eq!(eval("KBD > SCREEN & LOOP <= 9"), Ok(0));
+        assert_eq!(eval("1 << 2 > 3"), Ok(1));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(eval("FOO+1"), Err("unknown symbol `FOO`".to_string()));
+        assert_eq!(eval("1/0"), Err("division by zero in `(1/0)`".to_string()));
+        assert_eq!(
+            eval("(1+2"),
+            Err("missing `)` in expression `(1+2`".to_string())
+        );
+        assert_eq!(
+            eval("1+2)"),
+            Err("unexpected `)` in expression `1+2)`".to_string())
+        );
+        assert_eq!(
+            eval("1+"),
+            Err("missing operand in expression `1+`".to_string())
+        );
+        assert_eq!(
+            eval("1 2"),
+            Err("missing operator in expression `1 2`".to_string())
+        );
+        assert_eq!(
+            eval("1 = 2"),
+            Err("unexpected `=` in expression `1 = 2`".to_string())
+        );
+        assert_eq!(eval("0x1G+1"), Err("invalid number `0x1G`".to_string()));
+        assert_eq!(eval("1<<64"), Err("overflow in `(1<<64)`".to_string()));
+        assert_eq!(eval("1<<63"), Err("overflow in `(1<<63)`".to_string()));
+        assert_eq!(eval("-1<<63"), Ok(i64::MIN));
+        assert_eq!(
+            eval("-(-1<<63)"),
+            Err("overflow in `-(-1<<63)`".to_string())
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use std::{fs, path::Path};
+
+use crate::{code::Code, toml};
+
+/// The shifts of the extended ISA, with their prefix and comp field.
+const SHIFTS: [(&str, &str); 6] = [
+    ("A<<", "1010000010"),
+    ("D<<", "1010000110"),
+    ("M<<", "1011000010"),
+    ("A>>", "1010000000"),
+    ("D>>", "1010000100"),
+    ("M>>", "1011000000"),
+];
+
+/// Mnemonics with their encoding, each encoding used once.
+type Table = Vec<(String, String)>;
+
+/// An instruction set: the computations of C-instructions with their prefix
+/// and comp field (10 bits, like `1110001100` for `D`), and the
+/// destinations and jumps with their 3 bits.
+///
+/// `standard` is the Hack one and `extended` adds shifts; others are loaded
+/// from a `.toml` file, see [`Isa::from_toml`].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Isa {
+    comps: Table,
+    dests: Table,
+    jumps: Table,
+}
+
+impl Default for Isa {
+    fn default() -> Self {
+        Self::standard()
+    }
+}
+
+/// The table of the bit patterns of `width` bits that have a mnemonic.
+fn table(width: u32, mnemonic: impl Fn(&str) -> Option<String>) -> Table {
+    (0..1 << width)
+        .map(|bits| format!("{bits:0width$b}", width = width as usize))
+        .filter_map(|bin| mnemonic(&bin).map(|mnemonic| (mnemonic, bin)))
+        .collect()
+}
+
+impl Isa {
+    pub fn standard() -> Self {
+        Self {
+            comps: table(7, Code::comp_mnemonic)
+                .into_iter()
+                .map(|(mnemonic, bin)| (mnemonic, format!("111{bin}")))
+                .collect(),
+            dests: table(3, Code::dest_mnemonic),
+            jumps: table(3, Code::jump_mnemonic),
+        }
+    }
+
+    /// The standard ISA with shifts, encoded with the `101` prefix.
+    pub fn extended() -> Self {
+        let mut isa = Self::standard();
+        isa.comps.extend(
+            SHIFTS
+                .iter()
+                .map(|&(mnemonic, bin)| (mnemonic.to_string(), bin.to_string())),
+        );
+        isa
+    }
+
+    /// The ISA named by `--isa`: `standard`, `extended`, or a `.toml` file.
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "standard" => Ok(Self::standard()),
+            "extended" => Ok(Self::extended()),
+            _ if name.ends_with(".toml") => Self::load(Path::new(name)),
+            _ => Err(format!(
+                "unknown ISA `{name}`, expected `standard`, `extended` or a `.toml` file"
+            )),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read `{}`: {e}", path.display()))?;
+        Self::from_toml(&text).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    /// Read an ISA from a TOML table file like:
+    ///
+    /// ```toml
+    /// extends = "standard"    # or "extended", or nothing to start empty
+    ///
+    /// [comp]
+    /// "D*A" = "100_0000000"   # prefix and comp field
+    ///
+    /// [dest]
+    /// [jump]
+    /// ```
+    ///
+    /// Entries add to the ISA it extends, or replace its entries with the same
+    /// mnemonic. Two mnemonics with the same encoding are an error.
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        let mut isa = Self {
+            comps: Table::new(),
+            dests: Table::new(),
+            jumps: Table::new(),
+        };
+        for entry in toml::parse(text, &["comp", "dest", "jump"])? {
+            let (key, value) = (entry.key, entry.string()?);
+            let (table, width) = match entry.table {
+                None if key == "extends" => {
+                    if !isa.comps.is_empty() || !isa.dests.is_empty() || !isa.jumps.is_empty() {
+                        return Err(entry.error("`extends` must come first"));
+                    }
+                    isa = match value {
+                        "standard" => Self::standard(),
+                        "extended" => Self::extended(),
+                        _ => return Err(entry.error(format!("unknown ISA `{value}`"))),
+                    };
+                    continue;
+                }
+                None => return Err(entry.error(format!("unknown key `{key}`"))),
+                Some("comp") => (&mut isa.comps, 10),
+                Some("dest") => (&mut isa.dests, 3),
+                _ => (&mut isa.jumps, 3),
+            };
+            let bin = value.replace('_', "");
+            if bin.len() != width || !bin.chars().all(|c| c == '0' || c == '1') {
+                return Err(entry.error(format!(
+                    "`{key}` is encoded as `{value}`, but it must be {width} binary digits"
+                )));
+            }
+            if width == 10 && !bin.starts_with('1') {
+                return Err(entry.error(format!(
+                    "`{key}` is encoded as `{value}`, but C-instructions start with 1"
+                )));
+            }
+            if key.is_empty() && width == 10
+                || key.contains(|c: char| c.is_whitespace() || c == '=' || c == ';')
+            {
+                return Err(entry.error(format!("invalid mnemonic `{key}`")));
+            }
+            table.retain(|(mnemonic, _)| mnemonic != key);
+            if let Some((other, _)) = table.iter().find(|(_, other)| *other == bin) {
+                return Err(entry.error(format!(
+                    "`{key}` and `{other}` are both encoded as `{value}`"
+                )));
+            }
+            table.push((key.to_string(), bin));
+        }
+        Ok(isa)
+    }
+
+    /// The mnemonic and bits of a computation, ignoring whitespace.
+    pub fn comp(&self, ins: &str) -> Option<(&str, &str)> {
+        let ins = ins.split_whitespace().collect::<String>();
+        find(&self.comps, |(mnemonic, _)| *mnemonic == ins)
+    }
+
+    /// The mnemonic and bits of a destination, ignoring whitespace; `A`,
+    /// `D` and `M` can be in any order.
+    pub fn dest(&self, ins: &str) -> Option<(&str, &str)> {
+        let ins = ins.split_whitespace().collect::<String>();
+        find(&self.dests, |(mnemonic, _)| *mnemonic == ins).or_else(|| {
+            let registers = Code::dest(&ins)?;
+            find(&self.dests, |(mnemonic, _)| {
+                Code::dest(mnemonic).as_ref() == Some(&registers)
+            })
+        })
+    }
+
+    pub fn jump(&self, ins: &str) -> Option<(&str, &str)> {
+        find(&self.jumps, |(mnemonic, _)| mnemonic == ins)
+    }
+
+    pub fn comp_mnemonic(&self, bin: &str) -> Option<&str> {
+        find(&self.comps, |(_, bits)| bits == bin).map(|(mnemonic, _)| mnemonic)
+    }
+
+    pub fn dest_mnemonic(&self, bin: &str) -> Option<&str> {
+        find(&self.dests, |(_, bits)| bits == bin).map(|(mnemonic, _)| mnemonic)
+    }
+
+    pub fn jump_mnemonic(&self, bin: &str) -> Option<&str> {
+        find(&self.jumps, |(_, bits)| bits == bin).map(|(mnemonic, _)| mnemonic)
+    }
+}
+
+fn find(table: &Table, predicate: impl Fn(&(String, String)) -> bool) -> Option<(&str, &str)> {
+    table
+        .iter()
+        .find(|entry| predicate(entry))
+        .map(|(mnemonic, bin)| (mnemonic.as_str(), bin.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_standard() {
+        let isa = Isa::standard();
+        assert_eq!(isa.comp("D + 1"), Some(("D+1", "1110011111")));
+        assert_eq!(isa.comp("M"), Some(("M", "1111110000")));
+        assert_eq!(isa.comp("D<<"), None);
+        assert_eq!(isa.dest("MD"), Some(("MD", "011")));
+        assert_eq!(isa.dest("DM"), Some(("MD", "011")));
+        assert_eq!(isa.dest(" A M "), Some(("AM", "101")));
+        assert_eq!(isa.dest("AA"), None);
+        assert_eq!(isa.comp_mnemonic("1110101010"), Some("0"));
+        assert_eq!(isa.dest_mnemonic("000"), Some(""));
+    }
+
+    #[test]
+    fn test_jump() {
+        let isa = Isa::standard();
+        assert_eq!(isa.jump(""), Some(("", "000")));
+        assert_eq!(isa.jump("JGT"), Some(("JGT", "001")));
+        assert_eq!(isa.jump("JEQ"), Some(("JEQ", "010")));
+        assert_eq!(isa.jump("JGE"), Some(("JGE", "011")));
+        assert_eq!(isa.jump("JLT"), Some(("JLT", "100")));
+        assert_eq!(isa.jump("JNE"), Some(("JNE", "101")));
+        assert_eq!(isa.jump("JLE"), Some(("JLE", "110")));
+        assert_eq!(isa.jump("JMP"), Some(("JMP", "111")));
+        assert_eq!(isa.jump("JXX"), None);
+    }
+
+    #[test]
+    fn test_extended() {
+        assert_eq!(Isa::parse("extended"), Ok(Isa::extended()));
+        assert_eq!(
+            Isa::parse("x86"),
+            Err("unknown ISA `x86`, expected `standard`, `extended` or a `.toml` file".to_string())
+        );
+        let isa = Isa::extended();
+        assert_eq!(isa.comp("D<<"), Some(("D<<", "1010000110")));
+        assert_eq!(isa.comp("M >>"), Some(("M>>", "1011000000")));
+        assert_eq!(isa.comp("D<"), None);
+        for (mnemonic, bin) in SHIFTS {
+            assert_eq!(isa.comp_mnemonic(bin), Some(mnemonic));
+        }
+    }
+
+    #[test]
+    fn test_from_toml() {
+        let isa = Isa::from_toml(
+            "# multiply, and shifts
+extends = \"extended\"
+
+[comp]
+\"D*A\" = \"100_0000000\"
+\"D*M\" = \"100_1000000\"  # with M
+",
+        )
+        .unwrap();
+        assert_eq!(isa.comp("D*A"), Some(("D*A", "1000000000")));
+        assert_eq!(isa.comp("D<<"), Some(("D<<", "1010000110")));
+        assert_eq!(isa.dest("DM"), Some(("MD", "011")));
+        assert_eq!(isa.jump("JMP"), Some(("JMP", "111")));
+
+        let isa = Isa::from_toml(
+            "[dest]\n\"\" = \"000\"\nB = \"101\"\n[jump]\nALWAYS = \"111\"\n\"\" = \"000\"",
+        )
+        .unwrap();
+        assert_eq!(isa.dest("B"), Some(("B", "101")));
+        assert_eq!(isa.dest_mnemonic("101"), Some("B"));
+        assert_eq!(isa.dest("AM"), None);
+        assert_eq!(isa.jump("ALWAYS"), Some(("ALWAYS", "111")));
+        assert_eq!(isa.comp("0"), None);
+        assert_eq!(
+            Isa::load(Path::new("test_files/Mul.toml")).map(|_| ()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_toml_errors() {
+        let error = |text| Isa::from_toml(text).unwrap_err();
+        assert_eq!(
+            error("extends = \"standard\"\n[comp]\n\"D*A\" = \"111_0001100\""),
+            "line 3: `D*A` and `D` are both encoded as `111_0001100`"
+        );
+        assert_eq!(
+            error("[dest]\nAM = \"1010\""),
+            "line 2: `AM` is encoded as `1010`, but it must be 3 binary digits"
+        );
+        assert_eq!(
+            error("[comp]\nX = \"0110000000\""),
+            "line 2: `X` is encoded as `0110000000`, but C-instructions start with 1"
+        );
+        assert_eq!(
+            error("[comp]\n\"D;A\" = \"1000000000\""),
+            "line 2: invalid mnemonic `D;A`"
+        );
+        assert_eq!(error("[alu]"), "line 1: unknown table `[alu]`");
+        assert_eq!(error("name = \"mine\""), "line 1: unknown key `name`");
+        assert_eq!(
+            error("[comp]\n\"D*A\" = 100"),
+            "line 2: `D*A` needs a quoted string"
+        );
+        assert_eq!(
+            error("extends = \"standard\"\nextends = \"extended\""),
+            "line 2: `extends` must come first"
+        );
+        assert!(Isa::parse("missing.toml")
+            .unwrap_err()
+            .starts_with("cannot read `missing.toml`"));
+    }
+}
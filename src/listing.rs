@@ -0,0 +1,109 @@
+use std::{fmt, rc::Rc};
+
+use crate::diagnostic::Expansion;
+
+/// A line of source, with the address and words it was assembled to.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ListingLine {
+    pub address: usize,
+    /// The 16 bit binary words, none for labels and most directives.
+    pub words: Vec<String>,
+    pub text: String,
+    /// The pseudo-instruction the words come from, which is listed instead
+    /// of the instructions it expands to.
+    pseudo_op: Option<Rc<Expansion>>,
+}
+
+/// The assembled program side by side with its source, written to
+/// `XXX.lst` with `--listing`.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Listing {
+    pub lines: Vec<ListingLine>,
+}
+
+impl Listing {
+    pub fn push(&mut self, address: usize, words: &[String], text: &str) {
+        self.lines.push(ListingLine {
+            address,
+            words: words.to_vec(),
+            text: text.to_string(),
+            pseudo_op: None,
+        });
+    }
+
+    /// Add the words of an instruction expanded from `pseudo_op`: the first
+    /// one starts a line with the pseudo-instruction, the others add to it.
+    pub fn push_pseudo_op(&mut self, pseudo_op: &Rc<Expansion>, address: usize, words: &[String]) {
+        match self.lines.last_mut() {
+            Some(line)
+                if line
+                    .pseudo_op
+                    .as_ref()
+                    .is_some_and(|p| Rc::ptr_eq(p, pseudo_op)) =>
+            {
+                line.words.extend_from_slice(words);
+            }
+            _ => self.lines.push(ListingLine {
+                address,
+                words: words.to_vec(),
+                text: pseudo_op.name.clone(),
+                pseudo_op: Some(pseudo_op.clone()),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Listing {
+    /// Each line is the address and word in hex, the word in binary and the
+    /// source; a line that assembles to several words continues on the next
+    /// lines without source.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            let Some((first, rest)) = line.words.split_first() else {
+                writeln!(f, "{:30}{}", "", line.text)?;
+                continue;
+            };
+            let hex = |word: &str| u16::from_str_radix(word, 2).unwrap();
+            writeln!(
+                f,
+                "{:04X}  {:04X}  {first}  {}",
+                line.address,
+                hex(first),
+                line.text
+            )?;
+            for (i, word) in rest.iter().enumerate() {
+                writeln!(f, "{:04X}  {:04X}  {word}", line.address + i + 1, hex(word))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let mut listing = Listing::default();
+        listing.push(0, &[], "(START)");
+        listing.push(0, &["0100000000000000".to_string()], "@SCREEN");
+        listing.push(
+            1,
+            &[
+                "1110110000010000".to_string(),
+                "0000000000000111".to_string(),
+            ],
+            ".word 0xEC10, 7",
+        );
+
+        assert_eq!(
+            listing.to_string(),
+            "                              (START)
+0000  4000  0100000000000000  @SCREEN
+0001  EC10  1110110000010000  .word 0xEC10, 7
+0002  0007  0000000000000111
+"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+/// Parse a numeric literal: decimal `16384`, hex `0x4000`, binary
+/// `0b0100_0000_0000_0000` or a character `'A'`.
+///
+/// Return `None` if `s` is not a numeric literal at all (so it's a symbol),
+/// and an error if it looks like one but is malformed or doesn't fit in a
+/// 16 bit word.
+pub fn parse_number(s: &str) -> Option<Result<usize, String>> {
+    if let Some(c) = s.strip_prefix('\'') {
+        return Some(parse_char(s, c));
+    }
+    if !s.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let (digits, radix) = if let Some(hex) = s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        (hex, 16)
+    } else if let Some(bin) = s.strip_prefix("0b").or(s.strip_prefix("0B")) {
+        (bin, 2)
+    } else {
+        (s, 10)
+    };
+    // `_` may separate groups of digits, but not start or end them
+    let digits_ok = !digits.is_empty()
+        && !digits.starts_with('_')
+        && !digits.ends_with('_')
+        && digits.chars().all(|c| c == '_' || c.is_digit(radix));
+    if !digits_ok {
+        return Some(Err(format!("invalid number `{s}`")));
+    }
+
+    let value = usize::from_str_radix(&digits.replace('_', ""), radix);
+    Some(match value {
+        Ok(value) if value <= u16::MAX as usize => Ok(value),
+        _ => Err(format!("number `{s}` doesn't fit in 16 bits")),
+    })
+}
+
+fn parse_char(s: &str, c: &str) -> Result<usize, String> {
+    let mut chars = c.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(c), Some('\''), None) if c == ' ' || c.is_ascii_graphic() => Ok(c as usize),
+        (Some(_), Some('\''), None) => {
+            Err(format!("character {s} is not a printable ASCII character"))
+        }
+        _ => Err(format!("invalid character literal `{s}`")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decimal() {
+        assert_eq!(parse_number("0"), Some(Ok(0)));
+        assert_eq!(parse_number("16384"), Some(Ok(16384)));
+        assert_eq!(parse_number("65535"), Some(Ok(65535)));
+        assert_eq!(
+            parse_number("65536"),
+            Some(Err("number `65536` doesn't fit in 16 bits".to_string()))
+        );
+        assert_eq!(
+            parse_number("12abc"),
+            Some(Err("invalid number `12abc`".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(parse_number("0x4000"), Some(Ok(16384)));
+        assert_eq!(parse_number("0XfFfF"), Some(Ok(65535)));
+        assert_eq!(parse_number("0x7F_00"), Some(Ok(0x7f00)));
+        assert_eq!(
+            parse_number("0x10000"),
+            Some(Err("number `0x10000` doesn't fit in 16 bits".to_string()))
+        );
+        assert_eq!(
+            parse_number("0x"),
+            Some(Err("invalid number `0x`".to_string()))
+        );
+        assert_eq!(
+            parse_number("0x4g"),
+            Some(Err("invalid number `0x4g`".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_binary() {
+        assert_eq!(parse_number("0b1111000011110000"), Some(Ok(0xf0f0)));
+        assert_eq!(parse_number("0b1111_0000_1111_0000"), Some(Ok(0xf0f0)));
+        assert_eq!(
+            parse_number("0b_1"),
+            Some(Err("invalid number `0b_1`".to_string()))
+        );
+        assert_eq!(
+            parse_number("0b102"),
+            Some(Err("invalid number `0b102`".to_string()))
+        );
+        assert_eq!(
+            parse_number("0b1_0000_0000_0000_0000"),
+            Some(Err(
+                "number `0b1_0000_0000_0000_0000` doesn't fit in 16 bits".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_char() {
+        assert_eq!(parse_number("'A'"), Some(Ok(65)));
+        assert_eq!(parse_number("' '"), Some(Ok(32)));
+        assert_eq!(parse_number("'''"), Some(Ok(39)));
+        assert_eq!(
+            parse_number("'AB'"),
+            Some(Err("invalid character literal `'AB'`".to_string()))
+        );
+        assert_eq!(
+            parse_number("'A"),
+            Some(Err("invalid character literal `'A`".to_string()))
+        );
+        assert_eq!(
+            parse_number("'é'"),
+            Some(Err(
+                "character 'é' is not a printable ASCII character".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_symbol() {
+        assert_eq!(parse_number("LOOP"), None);
+        assert_eq!(parse_number("R0"), None);
+        assert_eq!(parse_number("_x1"), None);
+    }
+}
@@ -0,0 +1,343 @@
+use std::path::PathBuf;
+
+use crate::{expr::check_symbol, isa::Isa, number::parse_number, target::Target};
+
+pub const USAGE: &str = "usage: assembler [-I DIR]... [-D NAME[=VALUE]]... [--pseudo-ops] \
+                         [--full-alu] [--isa standard|extended|FILE.toml] [--target hack|bare] \
+                         [--symbols-file FILE.toml] [--legacy-labels] [--strict] \
+                         [--alloc-order usage|declaration|alphabetical] [--listing] [--report] \
+                         [--pad] [--fill WORD] FILE";
+
+/// The order variables get their address in, chosen with `--alloc-order`.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum AllocOrder {
+    /// Each variable where it's first used, or declared with `.var`.
+    #[default]
+    Usage,
+    /// The `.var` variables first, in the order they are declared, then the
+    /// others as they are used.
+    Declaration,
+    /// All variables in alphabetical order.
+    Alphabetical,
+}
+
+impl AllocOrder {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "usage" => Ok(Self::Usage),
+            "declaration" => Ok(Self::Declaration),
+            "alphabetical" => Ok(Self::Alphabetical),
+            _ => Err(format!(
+                "unknown allocation order `{name}`, expected `usage`, `declaration` or `alphabetical`"
+            )),
+        }
+    }
+}
+
+/// Command line options.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Options {
+    /// The `.asm` file to assemble, or the `.hack` file to disassemble.
+    pub input: PathBuf,
+    /// Directories searched by `.include`, after the including file's one.
+    pub include_dirs: Vec<PathBuf>,
+    /// Constants defined with `-D NAME=VALUE`, in order.
+    pub defines: Vec<(String, usize)>,
+    /// Accept pseudo-instructions like `goto LOOP`.
+    pub pseudo_ops: bool,
+    /// Accept every ALU computation, not only the documented ones, and
+    /// disassemble them.
+    pub full_alu: bool,
+    /// The instruction set, built in or loaded from a `.toml` file.
+    pub isa: Isa,
+    /// The predefined symbols and memory map, a `--target` profile updated by
+    /// `--symbols-file`.
+    pub target: Target,
+    /// Also write a listing, `XXX.lst` next to `XXX.hack`.
+    pub listing: bool,
+    /// Only warn about duplicate labels and labels named like predefined
+    /// symbols, keeping the first definition.
+    pub legacy_labels: bool,
+    /// Reject variables that aren't declared with `.var`.
+    pub strict: bool,
+    /// The order variables are allocated in, chosen with `--alloc-order`.
+    pub alloc_order: AllocOrder,
+    /// Print how much ROM and RAM the program uses.
+    pub report: bool,
+    /// Fill the ROM image up to its 32768 words.
+    pub pad: bool,
+    /// The word that `.org` and `--pad` fill the ROM with.
+    pub fill: u16,
+}
+
+impl Options {
+    /// Parse the command line arguments, without the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Self::default();
+        let mut input = None;
+        let mut symbols_files = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if let Some(dir) = arg.strip_prefix("-I") {
+                let dir = if dir.is_empty() {
+                    args.next().ok_or("`-I` needs a directory")?
+                } else {
+                    dir.to_string()
+                };
+                options.include_dirs.push(PathBuf::from(dir));
+            } else if let Some(define) = arg.strip_prefix("-D") {
+                let define = if define.is_empty() {
+                    args.next().ok_or("`-D` needs a constant name")?
+                } else {
+                    define.to_string()
+                };
+                options.defines.push(parse_define(&define)?);
+            } else if arg == "--pseudo-ops" {
+                options.pseudo_ops = true;
+            } else if arg == "--full-alu" {
+                options.full_alu = true;
+            } else if arg == "--isa" {
+                options.isa = Isa::parse(&args.next().ok_or("`--isa` needs an ISA name")?)?;
+            } else if arg == "--target" {
+                options.target =
+                    Target::parse(&args.next().ok_or("`--target` needs a target name")?)?;
+            } else if arg == "--symbols-file" {
+                symbols_files.push(PathBuf::from(
+                    args.next().ok_or("`--symbols-file` needs a file")?,
+                ));
+            } else if arg == "--listing" {
+                options.listing = true;
+            } else if arg == "--legacy-labels" {
+                options.legacy_labels = true;
+            } else if arg == "--strict" {
+                options.strict = true;
+            } else if arg == "--alloc-order" {
+                options.alloc_order =
+                    AllocOrder::parse(&args.next().ok_or("`--alloc-order` needs an order")?)?;
+            } else if arg == "--report" {
+                options.report = true;
+            } else if arg == "--pad" {
+                options.pad = true;
+            } else if arg == "--fill" {
+                let fill = args.next().ok_or("`--fill` needs a word")?;
+                options.fill = parse_number(&fill)
+                    .unwrap_or_else(|| Err(format!("invalid number `{fill}`")))
+                    .map_err(|e| format!("`--fill {fill}`: {e}"))?
+                    as u16;
+            } else if arg.starts_with('-') {
+                return Err(format!("unknown option `{arg}`"));
+            } else if input.replace(PathBuf::from(&arg)).is_some() {
+                return Err(format!(
+                    "unexpected argument `{arg}`, only one input file can be given"
+                ));
+            }
+        }
+        options.input = input.ok_or("no input file")?;
+        // the files update the target, wherever `--target` is
+        for path in &symbols_files {
+            options.target.load(path)?;
+        }
+        if let Some((name, _)) = options.defines.iter().find(|(name, _)| {
+            options
+                .target
+                .symbols
+                .iter()
+                .any(|(symbol, _)| symbol == name)
+        }) {
+            return Err(format!(
+                "`-D {name}`: `{name}` is a predefined symbol and can't be redefined"
+            ));
+        }
+
+        Ok(options)
+    }
+}
+
+/// Parse `NAME=VALUE`, or `NAME` alone which defines it as 1.
+fn parse_define(define: &str) -> Result<(String, usize), String> {
+    let (name, value) = match define.split_once('=') {
+        Some((name, value)) => {
+            let value = parse_number(value)
+                .unwrap_or_else(|| Err(format!("invalid number `{value}`")))
+                .map_err(|e| format!("`-D {define}`: {e}"))?;
+            (name, value)
+        }
+        None => (define, 1),
+    };
+    check_symbol("constant", name).map_err(|e| format!("`-D {define}`: {e}"))?;
+    Ok((name.to_string(), value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_input() {
+        assert_eq!(
+            parse(&["Max.asm"]),
+            Ok(Options {
+                input: PathBuf::from("Max.asm"),
+                ..Options::default()
+            })
+        );
+        assert_eq!(parse(&[]), Err("no input file".to_string()));
+        assert_eq!(
+            parse(&["Max.asm", "Pong.asm"]),
+            Err("unexpected argument `Pong.asm`, only one input file can be given".to_string())
+        );
+        assert_eq!(
+            parse(&["--listing", "Max.asm"]),
+            Ok(Options {
+                input: PathBuf::from("Max.asm"),
+                listing: true,
+                ..Options::default()
+            })
+        );
+        assert_eq!(
+            parse(&["-x", "Max.asm"]),
+            Err("unknown option `-x`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_include_dirs() {
+        assert_eq!(
+            parse(&["-I", "lib", "Max.asm", "-Ishared/asm"]).map(|options| options.include_dirs),
+            Ok(vec![PathBuf::from("lib"), PathBuf::from("shared/asm")])
+        );
+        assert_eq!(
+            parse(&["Max.asm", "-I"]),
+            Err("`-I` needs a directory".to_string())
+        );
+    }
+
+    #[test]
+    fn test_defines() {
+        assert_eq!(
+            parse(&["-D", "DEBUG", "-DLEVEL=0x10", "Max.asm", "-D", "X=0"])
+                .map(|options| options.defines),
+            Ok(vec![
+                ("DEBUG".to_string(), 1),
+                ("LEVEL".to_string(), 16),
+                ("X".to_string(), 0)
+            ])
+        );
+        assert_eq!(
+            parse(&["-D", "1X", "Max.asm"]),
+            Err("`-D 1X`: constant `1X` can't start with a digit".to_string())
+        );
+        assert_eq!(
+            parse(&["-D", "X=Y", "Max.asm"]),
+            Err("`-D X=Y`: invalid number `Y`".to_string())
+        );
+        assert_eq!(
+            parse(&["-D", "X=70000", "Max.asm"]),
+            Err("`-D X=70000`: number `70000` doesn't fit in 16 bits".to_string())
+        );
+        assert_eq!(
+            parse(&["Max.asm", "-D"]),
+            Err("`-D` needs a constant name".to_string())
+        );
+    }
+
+    #[test]
+    fn test_padding() {
+        assert_eq!(
+            parse(&["--pad", "--fill", "0xEA87", "Max.asm"])
+                .map(|options| (options.pad, options.fill)),
+            Ok((true, 0xEA87))
+        );
+        assert_eq!(
+            parse(&["Max.asm", "--fill", "JMP"]),
+            Err("`--fill JMP`: invalid number `JMP`".to_string())
+        );
+        assert_eq!(
+            parse(&["Max.asm", "--fill"]),
+            Err("`--fill` needs a word".to_string())
+        );
+    }
+
+    #[test]
+    fn test_isa() {
+        assert_eq!(
+            parse(&["--isa", "extended", "Max.asm"]).map(|options| options.isa),
+            Ok(Isa::extended())
+        );
+        assert_eq!(
+            parse(&["Max.asm"]).map(|options| options.isa),
+            Ok(Isa::standard())
+        );
+        assert_eq!(
+            parse(&["--isa", "arm", "Max.asm"]),
+            Err("unknown ISA `arm`, expected `standard`, `extended` or a `.toml` file".to_string())
+        );
+        assert_eq!(
+            parse(&["--isa", "test_files/Mul.toml", "Mul.asm"]).map(|options| options.isa),
+            Isa::load(std::path::Path::new("test_files/Mul.toml"))
+        );
+    }
+
+    #[test]
+    fn test_predefined_define() {
+        assert_eq!(
+            parse(&["-D", "SP=256", "Max.asm"]),
+            Err("`-D SP`: `SP` is a predefined symbol and can't be redefined".to_string())
+        );
+        assert_eq!(
+            parse(&["--target", "bare", "-D", "SP=256", "Max.asm"]).map(|options| options.defines),
+            Ok(vec![("SP".to_string(), 256)])
+        );
+    }
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(
+            parse(&["--strict", "--alloc-order", "alphabetical", "Max.asm"])
+                .map(|options| (options.strict, options.alloc_order)),
+            Ok((true, AllocOrder::Alphabetical))
+        );
+        assert_eq!(
+            parse(&["Max.asm"]).map(|options| (options.strict, options.alloc_order)),
+            Ok((false, AllocOrder::Usage))
+        );
+        assert_eq!(
+            parse(&["--alloc-order", "random", "Max.asm"]),
+            Err(
+                "unknown allocation order `random`, expected `usage`, `declaration` or `alphabetical`"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_target() {
+        assert_eq!(
+            parse(&["--target", "bare", "Max.asm"]).map(|options| options.target),
+            Ok(Target::bare())
+        );
+        let mut devices = Target::bare();
+        devices
+            .load(std::path::Path::new("test_files/Devices.toml"))
+            .unwrap();
+        assert_eq!(
+            parse(&[
+                "--symbols-file",
+                "test_files/Devices.toml",
+                "--target",
+                "bare",
+                "Max.asm"
+            ])
+            .map(|options| options.target),
+            Ok(devices)
+        );
+        assert_eq!(
+            parse(&["Max.asm", "--target"]),
+            Err("`--target` needs a target name".to_string())
+        );
+    }
+}
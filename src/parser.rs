@@ -1,7 +1,17 @@
 use std::{
     fs::File,
     io::{self, Read},
+    mem,
     path::Path,
+    rc::Rc,
+};
+
+use crate::{
+    diagnostic::{Diagnostic, Location, SourceFile},
+    expr::{check_symbol, is_symbol_char},
+    number::parse_number,
+    options::Options,
+    preprocessor::preprocess,
 };
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -9,33 +19,18 @@ pub enum InstructionType {
     AInstruction,
     CInstruction,
     LInstruction,
+    Directive,
 }
 
 impl InstructionType {
     fn is_a_ins(ins: &str) -> bool {
-        if ins.len() > 0 && ins.chars().next().unwrap() == '@' {
-            true
-        } else {
-            false
-        }
-    }
-    #[allow(unused)]
-    fn is_c_ins(ins: &str) -> bool {
-        // list all c-instruction possible, but I will sample skip it
-        match ins {
-            "M=1" => true,
-            // "D=1" => true,
-            // ...
-            // ...
-            _ => false,
-        }
+        ins.starts_with('@')
     }
     fn is_l_ins(ins: &str) -> bool {
-        if ins.len() > 0 && ins.chars().next().unwrap() == '(' {
-            true
-        } else {
-            false
-        }
+        ins.starts_with('(')
+    }
+    fn is_directive(ins: &str) -> bool {
+        ins.starts_with('.')
     }
     fn get_type(ins: &str) -> Self {
         let mut t = Self::CInstruction;
@@ -45,6 +40,9 @@ impl InstructionType {
         if Self::is_l_ins(ins) {
             t = Self::LInstruction;
         }
+        if Self::is_directive(ins) {
+            t = Self::Directive;
+        }
         t
     }
 }
@@ -55,11 +53,123 @@ pub struct Instruction {
     ins_raw: String,
 }
 
+impl Instruction {
+    /// The instruction as written, without the comment.
+    pub fn text(&self) -> &str {
+        &self.ins_raw
+    }
+
+    /// How many words of ROM the instruction takes.
+    fn size(&self) -> usize {
+        match self.ins_type {
+            InstructionType::AInstruction | InstructionType::CInstruction => 1,
+            InstructionType::LInstruction => 0,
+            InstructionType::Directive => match self.ins_raw.split_once(char::is_whitespace) {
+                Some((".word", args)) => args.split(',').count(),
+                _ => 0,
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SourceLine {
+    pub text: String,
+    pub location: Location,
+}
+
+/// Split source code into lines, numbered from 1.
+pub fn source_lines(source: &str, file: Option<Rc<SourceFile>>) -> Vec<SourceLine> {
+    source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| SourceLine {
+            text: text.to_string(),
+            location: Location {
+                file: file.clone(),
+                line: i + 1,
+                expansion: None,
+            },
+        })
+        .collect()
+}
+
+/// Remove the `//` comment from a line, unless it is in a quoted string, and
+/// the whitespace around what's left.
+pub fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '/' if !quoted && line[i..].starts_with("//") => return line[..i].trim(),
+            _ => (),
+        }
+    }
+    line.trim()
+}
+
+/// The text between the double quotes of `"text"`, which can't contain
+/// other double quotes or be empty.
+pub fn unquote(s: &str) -> Option<&str> {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .filter(|s| !s.is_empty() && !s.contains('"'))
+}
+
+/// Split the arguments of `.equ NAME value` (or `.equ NAME, value`) into
+/// the name and the value.
+pub fn split_constant(args: &str) -> Option<(&str, &str)> {
+    args.split_once(|c: char| c.is_whitespace() || c == ',')
+        .map(|(name, value)| (name, value.trim_start().trim_start_matches(',').trim()))
+        .filter(|(_, value)| !value.is_empty())
+}
+
+/// Split a C-instruction `dest=comp;jump` into its trimmed fields, where
+/// `dest=` and `;jump` are optional.
+fn c_fields(ins: &str) -> Result<(&str, &str, &str), String> {
+    if ins.matches('=').count() > 1 {
+        return Err("more than one `=`".to_string());
+    }
+    if ins.matches(';').count() > 1 {
+        return Err("more than one `;`".to_string());
+    }
+    let (dest, rest) = match ins.split_once('=') {
+        Some((dest, _)) if dest.contains(';') => {
+            return Err("the destination must come before the `;`".to_string());
+        }
+        Some((dest, _)) if dest.trim().is_empty() => {
+            return Err("no destination before `=`".to_string());
+        }
+        Some((dest, rest)) => (dest.trim(), rest),
+        None => ("", ins),
+    };
+    let (comp, jump) = match rest.split_once(';') {
+        Some((_, jump)) if jump.trim().is_empty() => {
+            return Err("no jump after `;`".to_string());
+        }
+        Some((comp, jump)) => (comp.trim(), jump.trim()),
+        None => (rest.trim(), ""),
+    };
+    if comp.is_empty() {
+        return Err("no computation".to_string());
+    }
+    Ok((dest, comp, jump))
+}
+
+/// Whether `symbol` names anonymous labels, like `+` or `--`: a run of `+`
+/// refers to the following `(+)` labels, a run of `-` to the preceding `(-)`.
+pub fn is_anonymous_label(symbol: &str) -> bool {
+    !symbol.is_empty() && (symbol.bytes().all(|b| b == b'+') || symbol.bytes().all(|b| b == b'-'))
+}
+
+#[derive(Clone)]
 pub struct Parser {
-    lines: Vec<String>,
+    lines: Vec<SourceLine>,
     next_line_number: usize,
     pub next_ins_address: usize,
     pub current_instruction: Option<Instruction>,
+    /// The last global label, which local labels like `.loop` belong to.
+    scope: Option<String>,
 }
 
 impl Parser {
@@ -67,21 +177,49 @@ impl Parser {
         let mut file = File::open(path)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
-        let lines = contents.lines().map(|s| String::from(s)).collect();
 
-        Ok(Self {
+        let file = SourceFile {
+            path: path.to_path_buf(),
+            included_from: None,
+        };
+        Ok(Self::from_lines(source_lines(
+            &contents,
+            Some(Rc::new(file)),
+        )))
+    }
+
+    #[cfg(test)]
+    pub fn from_source(source: &str) -> Self {
+        Self::from_lines(source_lines(source, None))
+    }
+
+    fn from_lines(lines: Vec<SourceLine>) -> Self {
+        Self {
             lines,
             next_line_number: 0,
             next_ins_address: 0,
             current_instruction: None,
-        })
+            scope: None,
+        }
+    }
+
+    /// Expand includes, macros and conditional blocks, before any line is
+    /// read.
+    pub fn preprocess(&mut self, options: &Options) -> Result<(), Diagnostic> {
+        self.lines = preprocess(mem::take(&mut self.lines), options)?;
+        Ok(())
+    }
+
+    /// Where the current instruction comes from.
+    pub fn location(&self) -> Location {
+        self.lines[self.next_line_number - 1].location.clone()
     }
 
     pub fn has_more_lines(&self) -> bool {
         self.next_line_number < self.lines.len()
     }
 
-    /// 0. if has no more line, return
+    /// 0. if has no more line, clear current ins, return
     /// 1. 读一行，去掉注释后内容
     /// 2. trim左右
     /// 3. 等于""，则跳过改行，取下一行，next line number + 1, 跳回 step 0.
@@ -89,27 +227,51 @@ impl Parser {
     pub fn advance(&mut self) {
         loop {
             if !self.has_more_lines() {
+                self.current_instruction = None;
                 return;
             }
-            let mut line = self.lines[self.next_line_number].clone();
+            let line = strip_comment(&self.lines[self.next_line_number].text);
             self.next_line_number += 1;
 
-            if let Some(index) = line.find("//") {
-                line = line[..index].to_string()
-            }
-            let line = line.trim();
-            if line != "" {
+            if !line.is_empty() {
                 let ins_raw = line.to_string();
                 let ins_type = InstructionType::get_type(&ins_raw);
-                if ins_type != InstructionType::LInstruction {
-                    self.next_ins_address += 1;
+                let instruction = Instruction { ins_type, ins_raw };
+                self.next_ins_address += instruction.size();
+                self.current_instruction = Some(instruction);
+                if self.current_instruction.as_ref().unwrap().ins_type
+                    == InstructionType::LInstruction
+                {
+                    // labels of macro expansions, like `(SKIP_\@)`, don't
+                    // start a scope
+                    let label = self.symbol();
+                    if !label.starts_with('.')
+                        && !is_anonymous_label(&label)
+                        && self.location().expansion.is_none()
+                    {
+                        self.scope = Some(label);
+                    }
                 }
-                self.current_instruction = Some(Instruction { ins_type, ins_raw });
                 return;
             }
         }
     }
 
+    /// Turn a local symbol like `.loop` into the full name it has in the
+    /// scope of the last global label, e.g. `Foo.bar.loop` after `(Foo.bar)`.
+    /// Other symbols are returned unchanged.
+    pub fn qualify(&self, symbol: &str) -> Result<String, String> {
+        if !symbol.starts_with('.') {
+            return Ok(symbol.to_string());
+        }
+        match &self.scope {
+            Some(scope) => Ok(format!("{scope}{symbol}")),
+            None => Err(format!(
+                "local symbol `{symbol}` is used before any global label"
+            )),
+        }
+    }
+
     pub fn symbol(&self) -> String {
         assert!(
             self.current_instruction.is_some(),
@@ -119,8 +281,12 @@ impl Parser {
         use InstructionType::*;
         match ins.ins_type {
             AInstruction => ins.ins_raw[1..].to_string(),
-            LInstruction => ins.ins_raw[1..(ins.ins_raw.len() - 1)].to_string(),
+            LInstruction => {
+                let label = &ins.ins_raw[1..];
+                label.strip_suffix(')').unwrap_or(label).to_string()
+            }
             CInstruction => panic!("Can't call symbol() in a C-Instruction"),
+            Directive => panic!("Can't call symbol() in a Directive"),
         }
     }
 
@@ -134,12 +300,9 @@ impl Parser {
         match ins.ins_type {
             AInstruction => panic!("Can't call dest() in a A-Instruction"),
             LInstruction => panic!("Can't call dest() in a L-Instruction"),
+            Directive => panic!("Can't call dest() in a Directive"),
             CInstruction => {
-                let mut dest = "";
-                let splited = ins.ins_raw.split("=").collect::<Vec<_>>();
-                if splited.len() == 2 {
-                    dest = splited[0];
-                }
+                let (dest, _, _) = c_fields(&ins.ins_raw).unwrap_or_default();
                 dest.to_string()
             }
         }
@@ -155,19 +318,10 @@ impl Parser {
         match ins.ins_type {
             AInstruction => panic!("Can't call comp() in a A-Instruction"),
             LInstruction => panic!("Can't call comp() in a L-Instruction"),
+            Directive => panic!("Can't call comp() in a Directive"),
             CInstruction => {
-                let dest;
-                let splited = ins.ins_raw.split("=").collect::<Vec<_>>();
-                let comp_and_jump;
-                if splited.len() == 2 {
-                    comp_and_jump = splited[1];
-                } else {
-                    comp_and_jump = splited[0];
-                }
-                let splited = comp_and_jump.split(";").collect::<Vec<_>>();
-                dest = splited[0];
-
-                dest.to_string()
+                let (_, comp, _) = c_fields(&ins.ins_raw).unwrap_or_default();
+                comp.to_string()
             }
         }
     }
@@ -182,17 +336,91 @@ impl Parser {
         match ins.ins_type {
             AInstruction => panic!("Can't call jump() in a A-Instruction"),
             LInstruction => panic!("Can't call jump() in a L-Instruction"),
+            Directive => panic!("Can't call jump() in a Directive"),
             CInstruction => {
-                let mut dest = "";
-                let splited = ins.ins_raw.split(";").collect::<Vec<_>>();
-                if splited.len() == 2 {
-                    dest = splited[1];
-                }
+                let (_, _, jump) = c_fields(&ins.ins_raw).unwrap_or_default();
+                jump.to_string()
+            }
+        }
+    }
 
-                dest.to_string()
+    /// Check the syntax of the current instruction: a label must be a valid
+    /// symbol in parentheses, an A-instruction must have a value, and a
+    /// C-instruction must be `dest=comp;jump` with optional `dest=` and
+    /// `;jump`. Directives are checked by the code handling them.
+    pub fn check(&self) -> Result<(), String> {
+        let Some(ins) = &self.current_instruction else {
+            return Ok(());
+        };
+        match ins.ins_type {
+            InstructionType::LInstruction => {
+                if !ins.ins_raw.ends_with(')') {
+                    return Err(format!(
+                        "label `{}` is missing its closing `)`",
+                        ins.ins_raw
+                    ));
+                }
+                match self.symbol() {
+                    label if label.is_empty() => Err("empty label `()`".to_string()),
+                    label if is_anonymous_label(&label) => Ok(()),
+                    label => check_symbol("label", &label),
+                }
             }
+            InstructionType::AInstruction => {
+                let value = self.symbol();
+                let value = value.trim();
+                if value.is_empty() {
+                    return Err("`@` needs a value".to_string());
+                }
+                // anything else than one word is an expression, checked when
+                // it is evaluated
+                if is_anonymous_label(value)
+                    || !value
+                        .chars()
+                        .all(|c| is_symbol_char(c) || c.is_whitespace())
+                {
+                    return Ok(());
+                }
+                // a malformed number rather than a symbol starting with a digit
+                let number = ["0x", "0X", "0b", "0B"]
+                    .iter()
+                    .any(|prefix| value.starts_with(prefix))
+                    || value.chars().all(|c| c.is_ascii_digit() || c == '_');
+                match parse_number(value) {
+                    Some(Ok(_)) => Ok(()),
+                    Some(Err(e)) if number => Err(e),
+                    Some(Err(_)) if value.chars().all(is_symbol_char) => Err(format!(
+                        "`{value}` is neither a number nor a symbol, symbols can't start with a digit"
+                    )),
+                    _ => check_symbol("symbol", value),
+                }
+            }
+            InstructionType::CInstruction => c_fields(&ins.ins_raw)
+                .map(|_| ())
+                .map_err(|e| format!("invalid C-instruction `{}`: {e}", ins.ins_raw)),
+            InstructionType::Directive => Ok(()),
         }
     }
+
+    /// Split a directive like `.equ ROW_WORDS 32` into its name (`equ`)
+    /// and its arguments (`ROW_WORDS 32`).
+    pub fn directive(&self) -> (String, String) {
+        assert!(
+            self.current_instruction.is_some(),
+            "Can't call directive() when have not instruction"
+        );
+        let ins = self.current_instruction.clone().unwrap();
+        assert!(
+            ins.ins_type == InstructionType::Directive,
+            "Can't call directive() in a {:?}",
+            ins.ins_type
+        );
+        let directive = &ins.ins_raw[1..];
+        let (name, args) = directive
+            .split_once(char::is_whitespace)
+            .unwrap_or((directive, ""));
+        (name.to_string(), args.trim().to_string())
+    }
 }
 
 #[cfg(test)]
@@ -200,6 +428,7 @@ mod tests {
     use std::{
         fs::{self, OpenOptions},
         io::{Seek, Write},
+        sync::atomic::{AtomicUsize, Ordering},
     };
 
     use super::*;
@@ -207,7 +436,7 @@ mod tests {
     #[test]
     fn test_lines() -> io::Result<()> {
         let test_file = TestFile::new()?;
-        let parser = Parser::new(&Path::new(&test_file.path))?;
+        let parser = Parser::new(Path::new(&test_file.path))?;
 
         assert_eq!(parser.lines.len(), test_file.total_lines);
 
@@ -217,7 +446,7 @@ mod tests {
     #[test]
     fn test_has_more_lines() -> io::Result<()> {
         let test_file = TestFile::new()?;
-        let mut parser = Parser::new(&Path::new(&test_file.path))?;
+        let mut parser = Parser::new(Path::new(&test_file.path))?;
 
         assert_eq!(parser.next_line_number, 0);
 
@@ -234,7 +463,7 @@ mod tests {
     fn test_empty() -> io::Result<()> {
         let mut test_file = TestFile::new()?;
         test_file.clear()?;
-        let parser = Parser::new(&Path::new(&test_file.path))?;
+        let parser = Parser::new(Path::new(&test_file.path))?;
 
         assert_eq!(parser.current_instruction, None);
         assert_eq!(parser.next_line_number, 0);
@@ -254,7 +483,7 @@ mod tests {
         test_file.add_line(" @123 //comment4")?;
         test_file.add_line(" M=1 //comment5")?;
         test_file.add_line("//comment6")?;
-        let mut parser = Parser::new(&Path::new(&test_file.path))?;
+        let mut parser = Parser::new(Path::new(&test_file.path))?;
         let prev_nln = parser.next_line_number;
 
         assert_eq!(parser.current_instruction, None);
@@ -303,13 +532,7 @@ mod tests {
 
         parser.advance();
 
-        assert_eq!(
-            parser.current_instruction,
-            Some(Instruction {
-                ins_raw: String::from("M=1"),
-                ins_type: InstructionType::CInstruction
-            })
-        );
+        assert_eq!(parser.current_instruction, None);
         assert_eq!(parser.next_line_number, prev_nln + 7);
         assert!(!parser.has_more_lines());
 
@@ -323,7 +546,7 @@ mod tests {
         test_file.add_line("1")?;
         test_file.add_line("M=1")?;
         test_file.add_line("M=1;JMP")?;
-        let mut parser = Parser::new(&Path::new(&test_file.path))?;
+        let mut parser = Parser::new(Path::new(&test_file.path))?;
 
         parser.advance();
         assert_eq!(parser.jump(), "".to_string());
@@ -341,7 +564,7 @@ mod tests {
         let mut test_file = TestFile::new().unwrap();
         test_file.clear().unwrap();
         test_file.add_line("@123").unwrap();
-        let mut parser = Parser::new(&Path::new(&test_file.path)).unwrap();
+        let mut parser = Parser::new(Path::new(&test_file.path)).unwrap();
 
         parser.advance();
         // should panic
@@ -354,7 +577,7 @@ mod tests {
         let mut test_file = TestFile::new().unwrap();
         test_file.clear().unwrap();
         test_file.add_line("(LOOP)").unwrap();
-        let mut parser = Parser::new(&Path::new(&test_file.path)).unwrap();
+        let mut parser = Parser::new(Path::new(&test_file.path)).unwrap();
 
         parser.advance();
         // should panic
@@ -367,7 +590,7 @@ mod tests {
         let mut test_file = TestFile::new().unwrap();
         test_file.clear().unwrap();
         test_file.add_line("//comment").unwrap();
-        let parser = Parser::new(&Path::new(&test_file.path)).unwrap();
+        let parser = Parser::new(Path::new(&test_file.path)).unwrap();
 
         // should panic
         parser.jump();
@@ -380,7 +603,7 @@ mod tests {
         test_file.add_line("1")?;
         test_file.add_line("M=1")?;
         test_file.add_line("M=1;JMP")?;
-        let mut parser = Parser::new(&Path::new(&test_file.path))?;
+        let mut parser = Parser::new(Path::new(&test_file.path))?;
 
         parser.advance();
         assert_eq!(parser.comp(), "1".to_string());
@@ -398,7 +621,7 @@ mod tests {
         let mut test_file = TestFile::new().unwrap();
         test_file.clear().unwrap();
         test_file.add_line("@123").unwrap();
-        let mut parser = Parser::new(&Path::new(&test_file.path)).unwrap();
+        let mut parser = Parser::new(Path::new(&test_file.path)).unwrap();
 
         parser.advance();
         // should panic
@@ -411,7 +634,7 @@ mod tests {
         let mut test_file = TestFile::new().unwrap();
         test_file.clear().unwrap();
         test_file.add_line("(LOOP)").unwrap();
-        let mut parser = Parser::new(&Path::new(&test_file.path)).unwrap();
+        let mut parser = Parser::new(Path::new(&test_file.path)).unwrap();
 
         parser.advance();
         // should panic
@@ -424,7 +647,7 @@ mod tests {
         let mut test_file = TestFile::new().unwrap();
         test_file.clear().unwrap();
         test_file.add_line("//comment").unwrap();
-        let parser = Parser::new(&Path::new(&test_file.path)).unwrap();
+        let parser = Parser::new(Path::new(&test_file.path)).unwrap();
 
         // should panic
         parser.comp();
@@ -442,7 +665,7 @@ mod tests {
         test_file.add_line("AM=1")?;
         test_file.add_line("AD=1")?;
         test_file.add_line("ADM=1")?;
-        let mut parser = Parser::new(&Path::new(&test_file.path))?;
+        let mut parser = Parser::new(Path::new(&test_file.path))?;
 
         parser.advance();
         assert_eq!(parser.dest(), "".to_string());
@@ -476,7 +699,7 @@ mod tests {
         let mut test_file = TestFile::new().unwrap();
         test_file.clear().unwrap();
         test_file.add_line("@123").unwrap();
-        let mut parser = Parser::new(&Path::new(&test_file.path)).unwrap();
+        let mut parser = Parser::new(Path::new(&test_file.path)).unwrap();
 
         parser.advance();
         // should panic
@@ -489,7 +712,7 @@ mod tests {
         let mut test_file = TestFile::new().unwrap();
         test_file.clear().unwrap();
         test_file.add_line("(LOOP)").unwrap();
-        let mut parser = Parser::new(&Path::new(&test_file.path)).unwrap();
+        let mut parser = Parser::new(Path::new(&test_file.path)).unwrap();
 
         parser.advance();
         // should panic
@@ -502,7 +725,7 @@ mod tests {
         let mut test_file = TestFile::new().unwrap();
         test_file.clear().unwrap();
         test_file.add_line("//comment").unwrap();
-        let parser = Parser::new(&Path::new(&test_file.path)).unwrap();
+        let parser = Parser::new(Path::new(&test_file.path)).unwrap();
 
         // should panic
         parser.dest();
@@ -515,7 +738,7 @@ mod tests {
         test_file.add_line("(LOOP)")?;
         test_file.add_line("@123")?;
         test_file.add_line("@num")?;
-        let mut parser = Parser::new(&Path::new(&test_file.path))?;
+        let mut parser = Parser::new(Path::new(&test_file.path))?;
 
         parser.advance();
         assert_eq!(parser.symbol(), "LOOP".to_string());
@@ -535,7 +758,7 @@ mod tests {
         let mut test_file = TestFile::new().unwrap();
         test_file.clear().unwrap();
         test_file.add_line("M=1").unwrap();
-        let mut parser = Parser::new(&Path::new(&test_file.path)).unwrap();
+        let mut parser = Parser::new(Path::new(&test_file.path)).unwrap();
 
         parser.advance();
         // should panic
@@ -548,17 +771,103 @@ mod tests {
         let mut test_file = TestFile::new().unwrap();
         test_file.clear().unwrap();
         test_file.add_line("//comment").unwrap();
-        let parser = Parser::new(&Path::new(&test_file.path)).unwrap();
+        let parser = Parser::new(Path::new(&test_file.path)).unwrap();
 
         // should panic
         parser.symbol();
     }
 
+    #[test]
+    fn test_directive() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line(".equ ROW_WORDS 32 // comment")?;
+        test_file.add_line("  .define\tWIDTH  512 ")?;
+        test_file.add_line(".endm")?;
+        test_file.add_line("@ROW_WORDS")?;
+        let mut parser = Parser::new(Path::new(&test_file.path))?;
+
+        parser.advance();
+        assert_eq!(
+            parser.current_instruction.as_ref().unwrap().ins_type,
+            InstructionType::Directive
+        );
+        assert_eq!(
+            parser.directive(),
+            ("equ".to_string(), "ROW_WORDS 32".to_string())
+        );
+        assert_eq!(parser.next_ins_address, 0);
+
+        parser.advance();
+        assert_eq!(
+            parser.directive(),
+            ("define".to_string(), "WIDTH  512".to_string())
+        );
+
+        parser.advance();
+        assert_eq!(parser.directive(), ("endm".to_string(), "".to_string()));
+        assert_eq!(parser.next_ins_address, 0);
+
+        parser.advance();
+        assert_eq!(parser.next_ins_address, 1);
+
+        assert_eq!(
+            strip_comment(" .include \"a//b.asm\" // lib "),
+            ".include \"a//b.asm\""
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic = "Can't call directive() in a AInstruction"]
+    fn test_directive_panic_in_a_ins() {
+        let mut test_file = TestFile::new().unwrap();
+        test_file.clear().unwrap();
+        test_file.add_line("@123").unwrap();
+        let mut parser = Parser::new(Path::new(&test_file.path)).unwrap();
+
+        parser.advance();
+        // should panic
+        parser.directive();
+    }
+
+    #[test]
+    fn test_qualify() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("@.start")?;
+        test_file.add_line("(Foo.bar)")?;
+        test_file.add_line("(.loop)")?;
+        test_file.add_line("@.loop")?;
+        test_file.add_line("(Foo.baz)")?;
+        let mut parser = Parser::new(Path::new(&test_file.path))?;
+
+        parser.advance();
+        assert_eq!(
+            parser.qualify(".start"),
+            Err("local symbol `.start` is used before any global label".to_string())
+        );
+        assert_eq!(parser.qualify("start"), Ok("start".to_string()));
+
+        parser.advance();
+        parser.advance();
+        assert_eq!(parser.qualify(".loop"), Ok("Foo.bar.loop".to_string()));
+        parser.advance();
+        assert_eq!(parser.qualify(".loop"), Ok("Foo.bar.loop".to_string()));
+        assert_eq!(parser.qualify("LOOP"), Ok("LOOP".to_string()));
+
+        parser.advance();
+        assert_eq!(parser.qualify(".loop"), Ok("Foo.baz.loop".to_string()));
+
+        Ok(())
+    }
+
     // test template
     // #[test]
     // fn test_xxx() -> io::Result<()> {
     //     let test_file = TestFile::new()?;
-    //     let parser = Parser::new(&Path::new(&test_file.path))?;
+    //     let parser = Parser::new(Path::new(&test_file.path))?;
 
     //     assert_eq!();
 
@@ -573,21 +882,25 @@ mod tests {
 
     impl TestFile {
         fn new() -> io::Result<Self> {
-            let path = "./test_lines.asm";
+            // tests run in parallel, so every test file needs its own path
+            static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+            let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+            let path = format!("./test_lines_{id}.asm");
             let total_lines = 10;
             let mut file = OpenOptions::new()
                 .read(true)
                 .write(true)
                 // .append(true)
+                .truncate(true)
                 .create(true)
-                .open(path)?;
+                .open(&path)?;
 
             for i in 0..total_lines {
                 file.write_all(format!("{i}\n").as_bytes())?
             }
             Ok(Self {
                 file,
-                path: path.to_string(),
+                path,
                 total_lines,
             })
         }
@@ -610,7 +923,74 @@ mod tests {
     impl Drop for TestFile {
         fn drop(&mut self) {
             fs::remove_file(&mut self.path)
-                .expect(format!("remove test file `{}` fail...", &self.path).as_str());
+                .unwrap_or_else(|_| panic!("remove test file `{}` fail...", &self.path));
         }
     }
+
+    fn check(line: &str) -> Result<(), String> {
+        let mut parser = Parser::from_source(line);
+        parser.advance();
+        parser.check()
+    }
+
+    #[test]
+    fn test_check() {
+        for line in [
+            "(LOOP)",
+            "(.loop)",
+            "(sys.init$ret:1)",
+            "(++)",
+            "@R0",
+            "@0x4000",
+            "@SCREEN + 32",
+            "@-",
+            "D=M",
+            "AM = M-1 ; JNE",
+            "0;JMP",
+            ".equ X 1",
+        ] {
+            assert_eq!(check(line), Ok(()), "{line}");
+        }
+    }
+
+    #[test]
+    fn test_check_errors() {
+        let error = |line| check(line).unwrap_err();
+        assert_eq!(error("(LOOP"), "label `(LOOP` is missing its closing `)`");
+        assert_eq!(error("("), "label `(` is missing its closing `)`");
+        assert_eq!(error("()"), "empty label `()`");
+        assert_eq!(
+            error("(LO OP)"),
+            "label `LO OP` can't contain ` `, only letters, digits, `_`, `.`, `$` and `:`"
+        );
+        assert_eq!(error("(1LOOP)"), "label `1LOOP` can't start with a digit");
+        assert_eq!(error("@"), "`@` needs a value");
+        assert_eq!(
+            error("@12abc"),
+            "`12abc` is neither a number nor a symbol, symbols can't start with a digit"
+        );
+        assert_eq!(error("@65536"), "number `65536` doesn't fit in 16 bits");
+        assert_eq!(
+            error("@LO OP"),
+            "symbol `LO OP` can't contain ` `, only letters, digits, `_`, `.`, `$` and `:`"
+        );
+        assert_eq!(
+            error("D=A=1"),
+            "invalid C-instruction `D=A=1`: more than one `=`"
+        );
+        assert_eq!(
+            error("D;JMP;JEQ"),
+            "invalid C-instruction `D;JMP;JEQ`: more than one `;`"
+        );
+        assert_eq!(
+            error("0;JMP=D"),
+            "invalid C-instruction `0;JMP=D`: the destination must come before the `;`"
+        );
+        assert_eq!(
+            error("=A"),
+            "invalid C-instruction `=A`: no destination before `=`"
+        );
+        assert_eq!(error("D="), "invalid C-instruction `D=`: no computation");
+        assert_eq!(error("D;"), "invalid C-instruction `D;`: no jump after `;`");
+    }
 }
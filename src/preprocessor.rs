@@ -0,0 +1,983 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    diagnostic::{Diagnostic, Expansion, ExpansionKind, Location, SourceFile},
+    expr::{check_symbol, is_symbol_char, Expr},
+    options::Options,
+    parser::{source_lines, split_constant, strip_comment, unquote, SourceLine},
+    pseudo,
+    symbol_table::SymbolTable,
+};
+
+/// Macros calling macros deeper than this are assumed to recurse forever.
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// The largest `.rept` count, enough to fill the ROM.
+const MAX_REPEAT_COUNT: i64 = 32768;
+
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<SourceLine>,
+}
+
+/// An `.if`, `.ifdef` or `.ifndef` block whose `.endif` isn't read yet.
+struct Conditional {
+    location: Location,
+    /// Whether the lines around the block are assembled.
+    outer_active: bool,
+    condition: bool,
+    in_else: bool,
+}
+
+impl Conditional {
+    fn is_active(&self) -> bool {
+        self.outer_active && self.condition != self.in_else
+    }
+}
+
+struct Preprocessor<'a> {
+    include_dirs: &'a [PathBuf],
+    pseudo_ops: bool,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    /// The symbols defined so far, for conditions: the value of predefined
+    /// symbols and constants, `None` for labels and constants that depend on
+    /// labels, whose value is only known when assembling.
+    symbols: HashMap<String, Option<i64>>,
+}
+
+/// Expand the includes and macros of a program.
+///
+/// `.include "path.asm"` is replaced by the lines of that file, searched
+/// next to the including file first, then in each of `include_dirs`.
+///
+/// A macro is defined with `.macro NAME param1, param2` and `.endm`, and
+/// called with `NAME arg1, arg2`. In the body, `\param` is replaced by the
+/// argument, `\@` by a number unique to each expansion (for labels local to
+/// the expansion) and `\()` by nothing, to end a parameter name.
+///
+/// `.if EXPR`, `.ifdef NAME` or `.ifndef NAME`, an optional `.else` and
+/// `.endif` assemble the lines of one branch only. Conditions can use the
+/// constants defined before them, in the source or with `-D`.
+///
+/// `.rept COUNT, NAME` and `.endr` repeat the lines between them, with
+/// `\NAME` (`\i` by default) replaced by the iteration number.
+///
+/// With `--pseudo-ops`, pseudo-instructions like `goto LOOP` are replaced by
+/// the instructions they stand for.
+pub fn preprocess(
+    lines: Vec<SourceLine>,
+    options: &Options,
+) -> Result<Vec<SourceLine>, Diagnostic> {
+    let mut symbols = SymbolTable::new(&options.target)
+        .table
+        .into_iter()
+        .map(|(name, symbol)| (name, Some(symbol.value as i64)))
+        .collect::<HashMap<_, _>>();
+    for (name, value) in &options.defines {
+        symbols.insert(name.clone(), Some(*value as i64));
+    }
+    let mut preprocessor = Preprocessor {
+        include_dirs: &options.include_dirs,
+        pseudo_ops: options.pseudo_ops,
+        macros: HashMap::new(),
+        expansions: 0,
+        symbols,
+    };
+    let mut output = Vec::new();
+    preprocessor.process(lines, 0, &mut output)?;
+    Ok(output)
+}
+
+fn split_first_word(code: &str) -> (&str, &str) {
+    let (word, rest) = code.split_once(char::is_whitespace).unwrap_or((code, ""));
+    (word, rest.trim())
+}
+
+impl Preprocessor<'_> {
+    fn process(
+        &mut self,
+        lines: Vec<SourceLine>,
+        depth: usize,
+        output: &mut Vec<SourceLine>,
+    ) -> Result<(), Diagnostic> {
+        let mut lines = lines.into_iter();
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        while let Some(line) = lines.next() {
+            let (word, rest) = split_first_word(strip_comment(&line.text));
+            let active = conditionals.last().is_none_or(Conditional::is_active);
+            match word {
+                ".if" | ".ifdef" | ".ifndef" => {
+                    // the condition of a skipped block may not make sense
+                    let condition = active && self.condition(word, rest, &line.location)?;
+                    conditionals.push(Conditional {
+                        location: line.location,
+                        outer_active: active,
+                        condition,
+                        in_else: false,
+                    });
+                }
+                ".else" => match conditionals.last_mut() {
+                    Some(conditional) if !conditional.in_else => conditional.in_else = true,
+                    Some(_) => {
+                        return Err(Diagnostic::new(line.location, "`.else` after `.else`"));
+                    }
+                    None => return Err(Diagnostic::new(line.location, "`.else` without `.if`")),
+                },
+                ".endif" => {
+                    if conditionals.pop().is_none() {
+                        return Err(Diagnostic::new(line.location, "`.endif` without `.if`"));
+                    }
+                }
+                _ if !active => (),
+                ".equ" | ".define" => {
+                    self.define_constant(rest);
+                    output.push(line);
+                }
+                _ if word.starts_with('(') => {
+                    let label = strip_comment(&line.text).trim_matches(['(', ')']);
+                    self.symbols.insert(label.to_string(), None);
+                    output.push(line);
+                }
+                ".include" => self.include(&line, rest, depth, output)?,
+                ".macro" => self.define(&line, rest, &mut lines)?,
+                ".endm" => {
+                    return Err(Diagnostic::new(line.location, "`.endm` without `.macro`"));
+                }
+                ".rept" => self.repeat(&line, rest, &mut lines, depth, output)?,
+                ".endr" => {
+                    return Err(Diagnostic::new(line.location, "`.endr` without `.rept`"));
+                }
+                name if self.macros.contains_key(name) => {
+                    self.expand(name, rest, &line, depth, output)?
+                }
+                op if self.pseudo_ops => match pseudo::expand(op, rest) {
+                    Some(Ok(texts)) => {
+                        let expansion = Rc::new(Expansion {
+                            kind: ExpansionKind::PseudoOp,
+                            name: strip_comment(&line.text).to_string(),
+                            call_site: line.location.clone(),
+                        });
+                        output.extend(texts.into_iter().map(|text| SourceLine {
+                            text,
+                            location: Location {
+                                expansion: Some(expansion.clone()),
+                                ..line.location.clone()
+                            },
+                        }));
+                    }
+                    Some(Err(message)) => return Err(Diagnostic::new(line.location, message)),
+                    None => output.push(line),
+                },
+                _ => output.push(line),
+            }
+        }
+        match conditionals.first() {
+            Some(conditional) => Err(Diagnostic::new(
+                conditional.location.clone(),
+                "`.if` has no matching `.endif`",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Evaluate the condition of `.if EXPR`, `.ifdef NAME` or `.ifndef NAME`.
+    fn condition(
+        &self,
+        directive: &str,
+        args: &str,
+        location: &Location,
+    ) -> Result<bool, Diagnostic> {
+        let error = |message: String| Diagnostic::new(location.clone(), message);
+        if directive == ".if" {
+            return Ok(self.value(directive, args).map_err(error)? != 0);
+        }
+        if args.is_empty() {
+            return Err(error(format!("`{directive}` needs a symbol name")));
+        }
+        check_symbol("symbol", args).map_err(error)?;
+        Ok(self.symbols.contains_key(args) == (directive == ".ifdef"))
+    }
+
+    /// Evaluate the argument of `.if` or `.rept`, which can only use symbols
+    /// whose value is known already.
+    fn value(&self, directive: &str, args: &str) -> Result<i64, String> {
+        Expr::parse(args)?.eval(&mut |symbol| match self.symbols.get(symbol) {
+            Some(Some(value)) => Ok(*value),
+            Some(None) => Err(format!(
+                "the value of `{symbol}` isn't known before assembling, so `{directive}` can't use it"
+            )),
+            None => Err(format!("undefined symbol `{symbol}`")),
+        })
+    }
+
+    /// Remember the constant defined by `.equ NAME value` for conditions.
+    /// Errors are left for the assembler to report.
+    fn define_constant(&mut self, args: &str) {
+        let Some((name, value)) = split_constant(args) else {
+            return;
+        };
+        let value = Expr::parse(value).ok().and_then(|expr| {
+            expr.eval(&mut |symbol| {
+                self.symbols
+                    .get(symbol)
+                    .copied()
+                    .flatten()
+                    .ok_or_else(String::new)
+            })
+            .ok()
+        });
+        self.symbols.entry(name.to_string()).or_insert(value);
+    }
+
+    fn include(
+        &mut self,
+        line: &SourceLine,
+        args: &str,
+        depth: usize,
+        output: &mut Vec<SourceLine>,
+    ) -> Result<(), Diagnostic> {
+        let error = |message: String| Diagnostic::new(line.location.clone(), message);
+        let Some(name) = unquote(args) else {
+            return Err(error("`.include` needs a quoted file name".to_string()));
+        };
+
+        let including_dir = match &line.location.file {
+            Some(file) => file.path.parent().unwrap_or(Path::new("")).to_path_buf(),
+            None => PathBuf::new(),
+        };
+        let path = [&including_dir]
+            .into_iter()
+            .chain(self.include_dirs)
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| error(format!("can't find included file `{name}`")))?;
+
+        // a file is in the include chain of the `.include` line if it is
+        // being included already
+        let canonical = fs::canonicalize(&path)
+            .map_err(|e| error(format!("can't read `{}`: {e}", path.display())))?;
+        let mut file = line.location.file.as_ref();
+        while let Some(f) = file {
+            if fs::canonicalize(&f.path).ok().as_ref() == Some(&canonical) {
+                return Err(error(format!(
+                    "include cycle: `{}` is already being included",
+                    path.display()
+                )));
+            }
+            file = f.included_from.as_ref().and_then(|l| l.file.as_ref());
+        }
+
+        let source = fs::read_to_string(&path)
+            .map_err(|e| error(format!("can't read `{}`: {e}", path.display())))?;
+        let file = SourceFile {
+            path,
+            included_from: Some(line.location.clone()),
+        };
+        self.process(source_lines(&source, Some(Rc::new(file))), depth, output)
+    }
+
+    fn define(
+        &mut self,
+        line: &SourceLine,
+        header: &str,
+        lines: &mut impl Iterator<Item = SourceLine>,
+    ) -> Result<(), Diagnostic> {
+        let error = |message: String| Diagnostic::new(line.location.clone(), message);
+        let (name, params) = split_first_word(header);
+        check_symbol("macro", name).map_err(error)?;
+        if self.macros.contains_key(name) {
+            return Err(error(format!("macro `{name}` is already defined")));
+        }
+        let params = params
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|param| !param.is_empty())
+            .map(String::from)
+            .collect::<Vec<_>>();
+        for param in &params {
+            check_symbol("macro parameter", param).map_err(error)?;
+        }
+
+        let mut body = Vec::new();
+        loop {
+            let Some(body_line) = lines.next() else {
+                return Err(error(format!("`.macro {name}` has no matching `.endm`")));
+            };
+            match split_first_word(strip_comment(&body_line.text)).0 {
+                ".endm" => break,
+                ".macro" => {
+                    return Err(Diagnostic::new(
+                        body_line.location,
+                        format!("`.macro` inside the definition of macro `{name}`"),
+                    ));
+                }
+                _ => body.push(body_line),
+            }
+        }
+        self.macros.insert(name.to_string(), Macro { params, body });
+
+        Ok(())
+    }
+
+    fn expand(
+        &mut self,
+        name: &str,
+        args: &str,
+        call: &SourceLine,
+        depth: usize,
+        output: &mut Vec<SourceLine>,
+    ) -> Result<(), Diagnostic> {
+        let error = |message: String| Diagnostic::new(call.location.clone(), message);
+        if depth >= MAX_EXPANSION_DEPTH {
+            return Err(error(format!(
+                "macro `{name}` is expanded more than {MAX_EXPANSION_DEPTH} levels deep"
+            )));
+        }
+        let mac = self.macros[name].clone();
+        let args = if args.is_empty() {
+            Vec::new()
+        } else {
+            args.split(',').map(str::trim).collect::<Vec<_>>()
+        };
+        if args.len() != mac.params.len() {
+            return Err(error(format!(
+                "macro `{name}` takes {} argument(s) but {} were given",
+                mac.params.len(),
+                args.len()
+            )));
+        }
+        if args.iter().any(|arg| arg.is_empty()) {
+            return Err(error(format!("empty argument in call of macro `{name}`")));
+        }
+
+        self.expansions += 1;
+        let id = self.expansions.to_string();
+        let expansion = Rc::new(Expansion {
+            kind: ExpansionKind::Macro,
+            name: name.to_string(),
+            call_site: call.location.clone(),
+        });
+        let body = substitute_lines(&mac.body, &mac.params, &args, &id, |location| Location {
+            expansion: Some(expansion.clone()),
+            ..location.clone()
+        })?;
+
+        self.process(body, depth + 1, output)
+    }
+
+    /// Read the body of `.rept COUNT, NAME` up to its `.endr`, and process it
+    /// COUNT times with `\NAME` replaced by 0, 1, ... COUNT-1.
+    fn repeat(
+        &mut self,
+        line: &SourceLine,
+        args: &str,
+        lines: &mut impl Iterator<Item = SourceLine>,
+        depth: usize,
+        output: &mut Vec<SourceLine>,
+    ) -> Result<(), Diagnostic> {
+        let error = |message: String| Diagnostic::new(line.location.clone(), message);
+        let (count, name) = split_rept_args(args);
+        check_symbol("`.rept` counter", name).map_err(error)?;
+        let count = self.value(".rept", count).map_err(error)?;
+        if !(0..=MAX_REPEAT_COUNT).contains(&count) {
+            return Err(error(format!(
+                "`.rept` count is {count}, but it must be from 0 to {MAX_REPEAT_COUNT}"
+            )));
+        }
+
+        // nested blocks are repeated with their enclosing one
+        let mut body = Vec::new();
+        let mut nesting = 0;
+        loop {
+            let Some(body_line) = lines.next() else {
+                return Err(error("`.rept` has no matching `.endr`".to_string()));
+            };
+            match split_first_word(strip_comment(&body_line.text)).0 {
+                ".rept" => nesting += 1,
+                ".endr" if nesting == 0 => break,
+                ".endr" => nesting -= 1,
+                _ => (),
+            }
+            body.push(body_line);
+        }
+
+        let params = [name.to_string()];
+        for i in 0..count {
+            self.expansions += 1;
+            let id = self.expansions.to_string();
+            let i = i.to_string();
+            let lines = substitute_lines(&body, &params, &[&i], &id, Location::clone)?;
+            self.process(lines, depth, output)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Substitute `params` by `args` in the lines of a macro or `.rept` body,
+/// with their location changed by `location`. Inside a nested `.rept`
+/// block, its counter is left for the block to substitute, even when an
+/// enclosing one has the same name.
+fn substitute_lines(
+    lines: &[SourceLine],
+    params: &[String],
+    args: &[&str],
+    id: &str,
+    location: impl Fn(&Location) -> Location,
+) -> Result<Vec<SourceLine>, Diagnostic> {
+    let mut params = params.to_vec();
+    let mut args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    let enclosing = params.len();
+    let mut output = Vec::new();
+    for line in lines {
+        let location = location(&line.location);
+        let text = substitute(strip_comment(&line.text), &params, &args, id)
+            .map_err(|message| Diagnostic::new(location.clone(), message))?;
+        match split_first_word(&text) {
+            (".rept", rept_args) => {
+                let counter = split_rept_args(rept_args).1.to_string();
+                args.push(format!("\\{counter}"));
+                params.push(counter);
+            }
+            // a stray `.endr` is reported when the body is processed
+            (".endr", _) if params.len() > enclosing => {
+                params.pop();
+                args.pop();
+            }
+            _ => (),
+        }
+        output.push(SourceLine { text, location });
+    }
+    Ok(output)
+}
+
+/// Split the arguments of `.rept COUNT, NAME` into the count expression and
+/// the counter name, which is `i` by default.
+fn split_rept_args(args: &str) -> (&str, &str) {
+    match args.rsplit_once(',') {
+        Some((count, name)) => (count.trim(), name.trim()),
+        None => (args, "i"),
+    }
+}
+
+/// Replace `\param`, `\@` and `\()` in a line of a macro body. The last
+/// parameter with a name wins, so that a nested `.rept` counter shadows the
+/// enclosing ones.
+fn substitute(code: &str, params: &[String], args: &[String], id: &str) -> Result<String, String> {
+    let mut output = String::new();
+    let mut rest = code;
+    while let Some(index) = rest.find('\\') {
+        output += &rest[..index];
+        rest = &rest[index + 1..];
+        if let Some(after) = rest.strip_prefix('@') {
+            output += id;
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("()") {
+            rest = after;
+        } else {
+            let end = rest.find(|c| !is_symbol_char(c)).unwrap_or(rest.len());
+            let param = &rest[..end];
+            let Some(i) = params.iter().rposition(|p| p == param) else {
+                return Err(format!("unknown macro parameter `\\{param}`"));
+            };
+            output += &args[i];
+            rest = &rest[end..];
+        }
+    }
+    Ok(output + rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn lines(source: &str) -> Vec<SourceLine> {
+        source_lines(source, None)
+    }
+
+    fn expand(source: &str) -> Result<Vec<String>, String> {
+        preprocess(lines(source), &Options::default())
+            .map(|lines| lines.into_iter().map(|line| line.text).collect())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_no_macros() {
+        let source = "// comment\n@1\n\nD=A // comment";
+
+        assert_eq!(
+            preprocess(lines(source), &Options::default()),
+            Ok(lines(source))
+        );
+    }
+
+    #[test]
+    fn test_expand() {
+        let source = "\
+.macro PUSH_D // push D onto the stack
+   @SP
+   A=M
+   M=D
+   @SP
+   M=M+1
+.endm
+.macro PUSH_CONST value
+   @\\value // comment with \\unknown
+   D=A
+   PUSH_D
+.endm
+   PUSH_CONST SCREEN + 1
+   PUSH_D";
+
+        assert_eq!(
+            expand(source),
+            Ok(vec![
+                "@SCREEN + 1".to_string(),
+                "D=A".to_string(),
+                "@SP".to_string(),
+                "A=M".to_string(),
+                "M=D".to_string(),
+                "@SP".to_string(),
+                "M=M+1".to_string(),
+                "@SP".to_string(),
+                "A=M".to_string(),
+                "M=D".to_string(),
+                "@SP".to_string(),
+                "M=M+1".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_params_and_unique_labels() {
+        let source = "\
+.macro COPY from, to
+   @\\from
+   D=M
+   @\\to\\()_\\@
+   M=D
+(SKIP_\\@)
+.endm
+   COPY a, b
+   COPY R1,R2";
+
+        assert_eq!(
+            expand(source),
+            Ok(vec![
+                "@a".to_string(),
+                "D=M".to_string(),
+                "@b_1".to_string(),
+                "M=D".to_string(),
+                "(SKIP_1)".to_string(),
+                "@R1".to_string(),
+                "D=M".to_string(),
+                "@R2_2".to_string(),
+                "M=D".to_string(),
+                "(SKIP_2)".to_string(),
+            ])
+        );
+    }
+
+    fn expand_with(source: &str, defines: &[(&str, usize)]) -> Result<Vec<String>, String> {
+        let options = Options {
+            defines: defines
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value))
+                .collect(),
+            ..Options::default()
+        };
+        preprocess(lines(source), &options)
+            .map(|lines| lines.into_iter().map(|line| line.text).collect())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let source = "\
+.equ LEVEL 2
+.if LEVEL > 1
+@1
+.ifdef DEBUG
+@2
+.else
+@3
+.endif
+.else
+@4
+.endif
+.ifndef LEVEL
+@5
+.if UNDEFINED // not evaluated
+.endif
+.endif";
+
+        assert_eq!(
+            expand_with(source, &[]),
+            Ok(vec![
+                ".equ LEVEL 2".to_string(),
+                "@1".to_string(),
+                "@3".to_string(),
+            ])
+        );
+        assert_eq!(
+            expand_with(source, &[("DEBUG", 0)]),
+            Ok(vec![
+                ".equ LEVEL 2".to_string(),
+                "@1".to_string(),
+                "@2".to_string(),
+            ])
+        );
+        assert_eq!(
+            expand_with(&source.replace("LEVEL 2", "LEVEL 1"), &[]),
+            Ok(vec![".equ LEVEL 1".to_string(), "@4".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_conditionals_in_macros() {
+        let source = "\
+.macro CHECK n
+.if \\n
+   @\\n
+.endif
+.endm
+(LOOP)
+.ifdef LOOP
+   CHECK 0
+   CHECK 3
+.endif";
+
+        assert_eq!(
+            expand(source),
+            Ok(vec!["(LOOP)".to_string(), "@3".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_repeat() {
+        let source = "\
+.equ ROWS 2
+.rept ROWS
+@SCREEN+32*\\i
+.rept 2, j
+@\\i\\j
+.endr
+.endr
+.rept 0
+@1
+.endr";
+
+        assert_eq!(
+            expand(source),
+            Ok(vec![
+                ".equ ROWS 2".to_string(),
+                "@SCREEN+32*0".to_string(),
+                "@00".to_string(),
+                "@01".to_string(),
+                "@SCREEN+32*1".to_string(),
+                "@10".to_string(),
+                "@11".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_nested_repeat_counters() {
+        let source = "\
+.rept 2
+@\\i
+.rept 2
+@\\i
+.endr
+.endr
+.macro COPY i
+@\\i
+.rept 2
+@\\i
+.endr
+.endm
+COPY R1";
+
+        assert_eq!(
+            expand(source),
+            Ok(["0", "0", "1", "1", "0", "1", "R1", "0", "1"]
+                .iter()
+                .map(|value| format!("@{value}"))
+                .collect())
+        );
+    }
+
+    #[test]
+    fn test_repeat_in_macro() {
+        let source = "\
+.macro FILL words, value
+.rept \\words
+@\\value+\\i
+(L\\@_\\i)
+.endr
+.endm
+FILL 2, SCREEN";
+
+        assert_eq!(
+            expand(source),
+            Ok(vec![
+                "@SCREEN+0".to_string(),
+                "(L1_0)".to_string(),
+                "@SCREEN+1".to_string(),
+                "(L1_1)".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_repeat_errors() {
+        assert_eq!(
+            expand(".rept 2\n@1"),
+            Err("line 1: `.rept` has no matching `.endr`".to_string())
+        );
+        assert_eq!(
+            expand("@1\n.endr"),
+            Err("line 2: `.endr` without `.rept`".to_string())
+        );
+        assert_eq!(
+            expand(".rept -1\n.endr"),
+            Err("line 1: `.rept` count is -1, but it must be from 0 to 32768".to_string())
+        );
+        assert_eq!(
+            expand("(L)\n.rept L\n.endr"),
+            Err(
+                "line 2: the value of `L` isn't known before assembling, so `.rept` can't use it"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            expand(".rept 2, 1x\n.endr"),
+            Err("line 1: `.rept` counter `1x` can't start with a digit".to_string())
+        );
+    }
+
+    #[test]
+    fn test_conditional_errors() {
+        assert_eq!(
+            expand("@1\n.if 1\n.if 0\n.endif"),
+            Err("line 2: `.if` has no matching `.endif`".to_string())
+        );
+        assert_eq!(
+            expand(".endif"),
+            Err("line 1: `.endif` without `.if`".to_string())
+        );
+        assert_eq!(
+            expand(".else"),
+            Err("line 1: `.else` without `.if`".to_string())
+        );
+        assert_eq!(
+            expand(".if 1\n.else\n.else\n.endif"),
+            Err("line 3: `.else` after `.else`".to_string())
+        );
+        assert_eq!(
+            expand(".if FOO\n.endif"),
+            Err("line 1: undefined symbol `FOO`".to_string())
+        );
+        assert_eq!(
+            expand("(LOOP)\n.equ END LOOP+1\n.if END\n.endif"),
+            Err(
+                "line 3: the value of `END` isn't known before assembling, so `.if` can't use it"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            expand(".ifdef 1\n.endif"),
+            Err("line 1: symbol `1` can't start with a digit".to_string())
+        );
+    }
+
+    /// A directory of source files, removed when dropped.
+    struct TestDir {
+        path: PathBuf,
+    }
+
+    impl TestDir {
+        fn new(files: &[(&str, &str)]) -> Self {
+            // tests run in parallel, so every test needs its own directory
+            static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+            let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+            let path =
+                std::env::temp_dir().join(format!("assembler_include_{}_{id}", std::process::id()));
+            for (name, source) in files {
+                let file = path.join(name);
+                fs::create_dir_all(file.parent().unwrap()).unwrap();
+                fs::write(file, source).unwrap();
+            }
+            Self { path }
+        }
+
+        fn preprocess(&self, name: &str, include_dirs: &[&str]) -> Result<Vec<SourceLine>, String> {
+            let path = self.path.join(name);
+            let file = SourceFile {
+                path: path.clone(),
+                included_from: None,
+            };
+            let lines = source_lines(&fs::read_to_string(&path).unwrap(), Some(Rc::new(file)));
+            let options = Options {
+                include_dirs: include_dirs.iter().map(|dir| self.path.join(dir)).collect(),
+                ..Options::default()
+            };
+            preprocess(lines, &options).map_err(|e| {
+                e.to_string()
+                    .replace(&format!("{}/", self.path.display()), "")
+            })
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.path).unwrap();
+        }
+    }
+
+    fn texts(lines: Vec<SourceLine>) -> Vec<String> {
+        lines.into_iter().map(|line| line.text).collect()
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = TestDir::new(&[
+            (
+                "main.asm",
+                "@1\n.include \"lib/push.asm\" // comment\nPUSH_D\n.include \"mult.asm\"",
+            ),
+            (
+                "lib/push.asm",
+                ".include \"stack.asm\"\n.macro PUSH_D\n@SP\n.endm",
+            ),
+            ("lib/stack.asm", "(STACK)"),
+            ("shared/mult.asm", "(MULT)\n@2"),
+        ]);
+        let lines = dir.preprocess("main.asm", &["shared"]).unwrap();
+
+        assert_eq!(
+            texts(lines.clone()),
+            ["@1", "(STACK)", "@SP", "(MULT)", "@2"]
+        );
+        let stack = lines[1].location.file.as_ref().unwrap();
+        assert_eq!(stack.path, dir.path.join("lib/stack.asm"));
+        let included_from = stack.included_from.as_ref().unwrap();
+        assert_eq!(included_from.line, 1);
+        assert_eq!(
+            included_from.file.as_ref().unwrap().path,
+            dir.path.join("lib/push.asm")
+        );
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let dir = TestDir::new(&[
+            ("main.asm", "@1\n.include \"a.asm\""),
+            ("a.asm", "\n.include \"b.asm\""),
+            ("b.asm", ".include \"a.asm\""),
+            ("missing.asm", ".include \"c.asm\""),
+            ("unquoted.asm", ".include c.asm"),
+            ("error.asm", ".include \"endm.asm\""),
+            ("endm.asm", "@1\n.endm"),
+        ]);
+
+        assert_eq!(
+            dir.preprocess("main.asm", &[]),
+            Err("b.asm:1: include cycle: `a.asm` is already being included
+  included from a.asm:2
+  included from main.asm:2"
+                .to_string())
+        );
+        assert_eq!(
+            dir.preprocess("missing.asm", &[]),
+            Err("missing.asm:1: can't find included file `c.asm`".to_string())
+        );
+        assert_eq!(
+            dir.preprocess("unquoted.asm", &[]),
+            Err("unquoted.asm:1: `.include` needs a quoted file name".to_string())
+        );
+        assert_eq!(
+            dir.preprocess("error.asm", &[]),
+            Err("endm.asm:2: `.endm` without `.macro`\n  included from error.asm:1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_locations() -> Result<(), Diagnostic> {
+        let source = "\
+.macro INNER
+   @1
+.endm
+.macro OUTER
+   INNER
+.endm
+   OUTER";
+        let expanded = preprocess(lines(source), &Options::default())?;
+
+        assert_eq!(expanded.len(), 1);
+        let location = &expanded[0].location;
+        assert_eq!(location.line, 2);
+        let inner = location.expansion.as_ref().unwrap();
+        assert_eq!(inner.name, "INNER");
+        assert_eq!(inner.call_site.line, 5);
+        let outer = inner.call_site.expansion.as_ref().unwrap();
+        assert_eq!(outer.name, "OUTER");
+        assert_eq!(outer.call_site, Location::from(7));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            expand(".macro M a\n@\\b\n.endm\nM 1"),
+            Err(
+                "line 2: unknown macro parameter `\\b`\n  in macro `M` called on line 4"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            expand(".macro M a, b\n.endm\nM 1"),
+            Err("line 3: macro `M` takes 2 argument(s) but 1 were given".to_string())
+        );
+        assert_eq!(
+            expand(".macro M a, b\n.endm\nM 1,"),
+            Err("line 3: empty argument in call of macro `M`".to_string())
+        );
+        assert_eq!(
+            expand(".macro M\n@1\n"),
+            Err("line 1: `.macro M` has no matching `.endm`".to_string())
+        );
+        assert_eq!(
+            expand(".macro M\n.endm\n.macro M\n.endm"),
+            Err("line 3: macro `M` is already defined".to_string())
+        );
+        assert_eq!(
+            expand(".macro M\n.macro N\n.endm"),
+            Err("line 2: `.macro` inside the definition of macro `M`".to_string())
+        );
+        assert_eq!(
+            expand("@1\n.endm"),
+            Err("line 2: `.endm` without `.macro`".to_string())
+        );
+        assert_eq!(
+            expand(".macro 1M\n.endm"),
+            Err("line 1: macro `1M` can't start with a digit".to_string())
+        );
+        assert_eq!(
+            expand(".macro M\nM\n.endm\nM"),
+            Err(format!(
+                "line 2: macro `M` is expanded more than 64 levels deep{}\n  in macro `M` called on line 4",
+                "\n  in macro `M` called on line 2".repeat(63)
+            ))
+        );
+    }
+}
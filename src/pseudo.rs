@@ -0,0 +1,110 @@
+/// The jump of each conditional jump pseudo-instruction, `jeq D, LABEL`...
+const JUMPS: [(&str, &str); 6] = [
+    ("jeq", "JEQ"),
+    ("jne", "JNE"),
+    ("jgt", "JGT"),
+    ("jge", "JGE"),
+    ("jlt", "JLT"),
+    ("jle", "JLE"),
+];
+
+/// Expand a pseudo-instruction like `goto LOOP` into the Hack instructions
+/// it stands for.
+///
+/// Return `None` if `op` is not a pseudo-instruction, and an error if its
+/// arguments are wrong.
+pub fn expand(op: &str, args: &str) -> Option<Result<Vec<String>, String>> {
+    let args = if args.is_empty() {
+        Vec::new()
+    } else {
+        args.split(',').map(str::trim).collect::<Vec<_>>()
+    };
+    let expected = match op {
+        "goto" | "push" | "pop" | "inc" | "dec" => 1,
+        "ld" => 2,
+        _ if JUMPS.iter().any(|&(name, _)| name == op) => 2,
+        _ => return None,
+    };
+    if args.len() != expected || args.iter().any(|arg| arg.is_empty()) {
+        return Some(Err(format!("`{op}` takes {expected} argument(s)")));
+    }
+
+    let register = |allowed: &[&str]| {
+        if allowed.contains(&args[0]) {
+            Ok(args[0])
+        } else {
+            let allowed = allowed
+                .iter()
+                .map(|r| format!("`{r}`"))
+                .collect::<Vec<_>>()
+                .join(" or ");
+            Err(format!("`{op}` works on {allowed}, not `{}`", args[0]))
+        }
+    };
+    let lines = match op {
+        "goto" => Ok(vec![format!("@{}", args[0]), "0;JMP".to_string()]),
+        "ld" => register(&["D", "A"]).map(|register| match register {
+            "A" => vec![format!("@{}", args[1])],
+            _ => vec![format!("@{}", args[1]), "D=A".to_string()],
+        }),
+        "push" => register(&["D"]).map(|_| {
+            ["@SP", "A=M", "M=D", "@SP", "M=M+1"]
+                .map(String::from)
+                .to_vec()
+        }),
+        "pop" => register(&["D"]).map(|_| ["@SP", "AM=M-1", "D=M"].map(String::from).to_vec()),
+        "inc" => register(&["A", "D", "M"]).map(|r| vec![format!("{r}={r}+1")]),
+        "dec" => register(&["A", "D", "M"]).map(|r| vec![format!("{r}={r}-1")]),
+        _ => {
+            let jump = JUMPS.iter().find(|&&(name, _)| name == op).unwrap().1;
+            register(&["D"]).map(|_| vec![format!("@{}", args[1]), format!("D;{jump}")])
+        }
+    };
+    Some(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_ok(op: &str, args: &str) -> Vec<String> {
+        expand(op, args).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(expand_ok("goto", "LOOP"), ["@LOOP", "0;JMP"]);
+        assert_eq!(expand_ok("jeq", "D, END"), ["@END", "D;JEQ"]);
+        assert_eq!(expand_ok("jle", "D,END"), ["@END", "D;JLE"]);
+        assert_eq!(expand_ok("ld", "D, 1234"), ["@1234", "D=A"]);
+        assert_eq!(expand_ok("ld", "A, SCREEN+1"), ["@SCREEN+1"]);
+        assert_eq!(
+            expand_ok("push", "D"),
+            ["@SP", "A=M", "M=D", "@SP", "M=M+1"]
+        );
+        assert_eq!(expand_ok("pop", "D"), ["@SP", "AM=M-1", "D=M"]);
+        assert_eq!(expand_ok("inc", "M"), ["M=M+1"]);
+        assert_eq!(expand_ok("dec", "D"), ["D=D-1"]);
+        assert_eq!(expand("D=A", ""), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            expand("goto", ""),
+            Some(Err("`goto` takes 1 argument(s)".to_string()))
+        );
+        assert_eq!(
+            expand("jeq", "D,"),
+            Some(Err("`jeq` takes 2 argument(s)".to_string()))
+        );
+        assert_eq!(
+            expand("ld", "M, 1"),
+            Some(Err("`ld` works on `D` or `A`, not `M`".to_string()))
+        );
+        assert_eq!(
+            expand("jgt", "M, END"),
+            Some(Err("`jgt` works on `D`, not `M`".to_string()))
+        );
+    }
+}
@@ -1,38 +1,146 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::RangeInclusive};
+
+use crate::{diagnostic::Location, target::Target};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SymbolKind {
+    Predefined,
+    Label,
+    Variable,
+    Constant,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Symbol {
+    pub value: usize,
+    pub kind: SymbolKind,
+    /// Where the symbol is defined, `None` for predefined symbols and
+    /// constants defined on the command line.
+    pub location: Option<Location>,
+}
+
+impl Symbol {
+    /// Where the symbol is defined, for error messages.
+    pub fn defined_on(&self) -> String {
+        match &self.location {
+            Some(location) => location.to_string(),
+            None => "the command line".to_string(),
+        }
+    }
+}
 
 pub struct SymbolTable {
-    pub table: HashMap<String, usize>,
+    pub table: HashMap<String, Symbol>,
     pub alloc_pos: usize,
+    /// The RAM variables are allocated in, and the regions around it.
+    variables: RangeInclusive<usize>,
+    reserved: Vec<(String, RangeInclusive<usize>)>,
+    /// The addresses of each variable, in allocation order.
+    pub variables_allocated: Vec<(String, RangeInclusive<usize>)>,
 }
 
 impl SymbolTable {
-    pub fn new() -> Self {
+    /// The table of the predefined symbols of `target`, allocating variables
+    /// from the start of its variable range.
+    pub fn new(target: &Target) -> Self {
         let mut t = Self {
             table: HashMap::new(),
-            alloc_pos: 16,
+            alloc_pos: *target.variables.start(),
+            variables: target.variables.clone(),
+            reserved: target.reserved.clone(),
+            variables_allocated: Vec::new(),
         };
-        for i in 0..16 {
-            t.add_entry(&format!("R{i}"), i);
+        for (name, value) in &target.symbols {
+            t.add_predefined(name, *value);
         }
-        t.add_entry("SP", 0);
-        t.add_entry("LCL", 1);
-        t.add_entry("ARG", 2);
-        t.add_entry("THIS", 3);
-        t.add_entry("THAT", 4);
-        t.add_entry("SCREEN", 16384);
-        t.add_entry("KBD", 24576);
         t
     }
 
-    pub fn add_entry(&mut self, k: &str, v: usize) {
-        self.table.insert(k.to_string(), v);
+    fn add_predefined(&mut self, k: &str, v: usize) {
+        self.add_entry(k, v, SymbolKind::Predefined, None);
+    }
+
+    pub fn add_entry(&mut self, k: &str, v: usize, kind: SymbolKind, location: Option<Location>) {
+        let symbol = Symbol {
+            value: v,
+            kind,
+            location,
+        };
+        self.table.insert(k.to_string(), symbol);
+    }
+
+    /// Allocate the next `size` free RAM addresses to the variable `k`,
+    /// skipping the addresses of predefined symbols. It's an error once the
+    /// variable range of the target is full.
+    pub fn add_variable(
+        &mut self,
+        k: &str,
+        size: usize,
+        location: Option<Location>,
+    ) -> Result<usize, String> {
+        let block = |start: usize| start..start + size;
+        while let Some(predefined) = self.table.values().find(|symbol| {
+            symbol.kind == SymbolKind::Predefined && block(self.alloc_pos).contains(&symbol.value)
+        }) {
+            self.alloc_pos = predefined.value + 1;
+        }
+        let (first, last) = (*self.variables.start(), *self.variables.end());
+        if self.alloc_pos + size > last + 1 {
+            let next = match self
+                .reserved
+                .iter()
+                .find(|(_, region)| region.contains(&(last + 1)))
+            {
+                Some((name, _)) => format!(", and {} is reserved for `{name}`", last + 1),
+                None => String::new(),
+            };
+            return Err(format!(
+                "no room for variable `{k}`: the addresses from {first} to {last} are all used{next}"
+            ));
+        }
+        let address = self.alloc_pos;
+        self.alloc_pos += size;
+        self.add_entry(k, address, SymbolKind::Variable, location);
+        self.variables_allocated
+            .push((k.to_string(), address..=address + size - 1));
+        Ok(address)
     }
 
     pub fn contains(&self, k: &str) -> bool {
         self.table.contains_key(k)
     }
 
+    pub fn get(&self, k: &str) -> Option<&Symbol> {
+        self.table.get(k)
+    }
+
+    /// Check that `k` can be defined, as a constant or a variable: it
+    /// mustn't be a predefined symbol or defined already.
+    pub fn check_undefined(&self, k: &str) -> Result<(), String> {
+        match self.table.get(k) {
+            Some(defined) if defined.kind == SymbolKind::Predefined => Err(format!(
+                "`{k}` is a predefined symbol and can't be redefined"
+            )),
+            Some(defined) => Err(format!(
+                "`{k}` is already defined on {}",
+                defined.defined_on()
+            )),
+            None => Ok(()),
+        }
+    }
+
     pub fn get_address(&self, k: &str) -> usize {
-        self.table.get(k).unwrap().clone()
+        self.table.get(k).unwrap().value
+    }
+
+    /// Find a symbol whose value is `v`, preferring named symbols like `SP`
+    /// or `SCREEN` over the `R0`-`R15` aliases.
+    pub fn find_symbol(&self, v: usize) -> Option<&str> {
+        let is_register = |k: &str| k.starts_with('R') && k[1..].parse::<usize>().is_ok();
+        self.table
+            .iter()
+            .filter(|(_, symbol)| symbol.value == v)
+            .map(|(k, _)| k.as_str())
+            .min_by_key(|&k| (is_register(k), k))
     }
 }
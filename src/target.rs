@@ -0,0 +1,189 @@
+use std::{fs, ops::RangeInclusive, path::Path};
+
+use crate::{expr::check_symbol, toml};
+
+/// The memory map of the machine a program runs on: its predefined
+/// symbols, the RAM where variables are allocated, and the regions reserved
+/// for memory-mapped devices.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Target {
+    pub symbols: Vec<(String, usize)>,
+    pub variables: RangeInclusive<usize>,
+    pub reserved: Vec<(String, RangeInclusive<usize>)>,
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Self::hack()
+    }
+}
+
+impl Target {
+    /// The Hack computer: `R0`-`R15`, the VM pointers `SP` to `THAT`, and the
+    /// screen and keyboard.
+    pub fn hack() -> Self {
+        let mut target = Self::bare();
+        target.symbols = (0..16).map(|i| (format!("R{i}"), i)).collect();
+        for (i, name) in ["SP", "LCL", "ARG", "THIS", "THAT"].iter().enumerate() {
+            target.symbols.push((name.to_string(), i));
+        }
+        target.symbols.push(("SCREEN".to_string(), 0x4000));
+        target.symbols.push(("KBD".to_string(), 0x6000));
+        target.variables = 16..=0x3fff;
+        target
+    }
+
+    /// The Hack computer without predefined symbols, for programs that don't
+    /// follow the VM conventions: variables can use all the RAM below the
+    /// screen.
+    pub fn bare() -> Self {
+        Self {
+            symbols: Vec::new(),
+            variables: 0..=0x3fff,
+            reserved: vec![
+                ("SCREEN".to_string(), 0x4000..=0x5fff),
+                ("KBD".to_string(), 0x6000..=0x6000),
+            ],
+        }
+    }
+
+    /// The target named by `--target`.
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "hack" => Ok(Self::hack()),
+            "bare" => Ok(Self::bare()),
+            _ => Err(format!(
+                "unknown target `{name}`, expected `hack` or `bare`"
+            )),
+        }
+    }
+
+    /// Add the symbols, variable range and reserved regions of a
+    /// `--symbols-file`.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read `{}`: {e}", path.display()))?;
+        self.update(&text)
+            .map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    /// Read a symbols file like:
+    ///
+    /// ```toml
+    /// [symbols]
+    /// LEDS = 0x6001
+    ///
+    /// [variables]
+    /// first = 16
+    /// last = 0x3FFF
+    ///
+    /// [reserved]
+    /// LEDS = [0x6001, 0x6001]     # first and last address
+    /// ```
+    ///
+    /// Entries replace those of the target with the same name.
+    pub fn update(&mut self, text: &str) -> Result<(), String> {
+        let mut variables = (*self.variables.start(), *self.variables.end());
+        for entry in toml::parse(text, &["symbols", "variables", "reserved"])? {
+            let key = entry.key;
+            match entry.table {
+                Some("symbols") => {
+                    check_symbol("symbol", key).map_err(|e| entry.error(e))?;
+                    let value = entry.number()?;
+                    self.symbols.retain(|(name, _)| name != key);
+                    self.symbols.push((key.to_string(), value));
+                }
+                Some("variables") if key == "first" => variables.0 = entry.number()?,
+                Some("variables") if key == "last" => variables.1 = entry.number()?,
+                Some("reserved") => {
+                    let (first, last) = entry.range()?;
+                    if first > last {
+                        return Err(entry
+                            .error(format!("the reserved region `{key}` ends before it starts")));
+                    }
+                    self.reserved.retain(|(name, _)| name != key);
+                    self.reserved.push((key.to_string(), first..=last));
+                }
+                _ => return Err(entry.error(format!("unknown key `{key}`"))),
+            }
+        }
+        let (first, last) = variables;
+        if first > last {
+            return Err(format!(
+                "the variables start at {first}, after their last address {last}"
+            ));
+        }
+        self.variables = first..=last;
+        if let Some((name, region)) = self.reserved.iter().find(|(_, region)| {
+            region.start() <= self.variables.end() && self.variables.start() <= region.end()
+        }) {
+            return Err(format!(
+                "the variables from {first} to {last} overlap the reserved region `{name}` \
+                 from {} to {}",
+                region.start(),
+                region.end()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let hack = Target::parse("hack").unwrap();
+        assert_eq!(hack, Target::default());
+        assert!(hack.symbols.contains(&("R15".to_string(), 15)));
+        assert!(hack.symbols.contains(&("KBD".to_string(), 24576)));
+        assert_eq!(hack.variables, 16..=16383);
+        assert_eq!(Target::parse("bare").unwrap().variables, 0..=16383);
+        assert_eq!(
+            Target::parse("pdp11"),
+            Err("unknown target `pdp11`, expected `hack` or `bare`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_symbols_file() {
+        let mut target = Target::hack();
+        target.load(Path::new("test_files/Devices.toml")).unwrap();
+        assert!(target.symbols.contains(&("LEDS".to_string(), 0x6001)));
+        assert!(target.symbols.contains(&("SP".to_string(), 0)));
+        assert_eq!(target.variables, 32..=0x3dff);
+        assert!(target
+            .reserved
+            .contains(&("FRAMEBUFFER".to_string(), 0x3e00..=0x3fff)));
+    }
+
+    #[test]
+    fn test_symbols_file_errors() {
+        let error = |text| Target::hack().update(text).unwrap_err();
+        assert_eq!(
+            error("[variables]\nlast = 0x4000"),
+            "the variables from 16 to 16384 overlap the reserved region `SCREEN` from 16384 to 24575"
+        );
+        assert_eq!(
+            error("[variables]\nfirst = 100\nlast = 99"),
+            "the variables start at 100, after their last address 99"
+        );
+        assert_eq!(
+            error("[reserved]\nROM = [10, 1]"),
+            "line 2: the reserved region `ROM` ends before it starts"
+        );
+        assert_eq!(
+            error("[symbols]\n1UP = 10"),
+            "line 2: symbol `1UP` can't start with a digit"
+        );
+        assert_eq!(
+            error("[variables]\nstart = 10"),
+            "line 2: unknown key `start`"
+        );
+        assert_eq!(
+            error("[symbols]\nLEDS = \"0x6001\""),
+            "line 2: `LEDS` needs a number"
+        );
+    }
+}
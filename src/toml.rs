@@ -0,0 +1,215 @@
+use crate::number::parse_number;
+
+/// A `key = value` line of a table file, in the `[table]` before it.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Entry<'a> {
+    pub line: usize,
+    pub table: Option<&'a str>,
+    pub key: &'a str,
+    pub value: Value<'a>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Value<'a> {
+    /// A string without escapes, like `"101_0000110"`.
+    String(&'a str),
+    /// A number like `0x4000`, see [`parse_number`].
+    Number(usize),
+    /// An array of numbers, like `[0x4000, 0x5FFF]`.
+    Array(Vec<usize>),
+}
+
+impl Entry<'_> {
+    pub fn error(&self, message: impl AsRef<str>) -> String {
+        format!("line {}: {}", self.line, message.as_ref())
+    }
+
+    pub fn string(&self) -> Result<&str, String> {
+        match self.value {
+            Value::String(s) => Ok(s),
+            _ => Err(self.error(format!("`{}` needs a quoted string", self.key))),
+        }
+    }
+
+    pub fn number(&self) -> Result<usize, String> {
+        match self.value {
+            Value::Number(n) => Ok(n),
+            _ => Err(self.error(format!("`{}` needs a number", self.key))),
+        }
+    }
+
+    /// A `[FIRST, LAST]` array.
+    pub fn range(&self) -> Result<(usize, usize), String> {
+        match self.value {
+            Value::Array(ref values) if values.len() == 2 => Ok((values[0], values[1])),
+            _ => Err(self.error(format!(
+                "`{}` needs the first and last address, like `[0x4000, 0x5FFF]`",
+                self.key
+            ))),
+        }
+    }
+}
+
+/// Read the `key = value` entries of a table file, the small part of TOML
+/// that the `--isa` and `--symbols-file` files need: `[table]` headers out of
+/// `tables`, bare or quoted keys, and strings, numbers or arrays of numbers.
+pub fn parse<'a>(text: &'a str, tables: &[&str]) -> Result<Vec<Entry<'a>>, String> {
+    let mut entries = Vec::new();
+    let mut table = None;
+    for (i, line) in text.lines().enumerate() {
+        let error = |message: String| format!("line {}: {message}", i + 1);
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let name = name.trim();
+            if !tables.contains(&name) {
+                return Err(error(format!("unknown table `[{name}]`")));
+            }
+            table = Some(name);
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .and_then(|(key, value)| Some((key_name(key.trim())?, value.trim())))
+            .ok_or_else(|| error("expected `key = value`".to_string()))?;
+        let value = parse_value(value)
+            .ok_or_else(|| error(format!("invalid value `{value}`")))?
+            .map_err(error)?;
+        entries.push(Entry {
+            line: i + 1,
+            table,
+            key,
+            value,
+        });
+    }
+    Ok(entries)
+}
+
+/// Remove a `#` comment, unless it is in a quoted string.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+/// A bare key like `JMP`, or a quoted one like `"D+1"`.
+fn key_name(key: &str) -> Option<&str> {
+    if key.starts_with('"') {
+        return string(key);
+    }
+    (!key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+    .then_some(key)
+}
+
+fn string(value: &str) -> Option<&str> {
+    value
+        .strip_prefix('"')?
+        .strip_suffix('"')
+        .filter(|s| !s.contains(['"', '\\']))
+}
+
+fn parse_value(value: &str) -> Option<Result<Value<'_>, String>> {
+    if let Some(s) = string(value) {
+        return Some(Ok(Value::String(s)));
+    }
+    if let Some(values) = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+        return values
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(parse_number)
+            .collect::<Option<Result<Vec<_>, _>>>()
+            .map(|values| values.map(Value::Array));
+    }
+    parse_number(value).map(|number| number.map(Value::Number))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = "# a comment
+top = \"level\"
+
+[comp]
+\"D#A\" = \"100_0000000\"  # with a `#`
+[ram]
+first = 0x10
+SCREEN = [16384, 0x5FFF]
+";
+        assert_eq!(
+            parse(text, &["comp", "ram"]),
+            Ok(vec![
+                Entry {
+                    line: 2,
+                    table: None,
+                    key: "top",
+                    value: Value::String("level"),
+                },
+                Entry {
+                    line: 5,
+                    table: Some("comp"),
+                    key: "D#A",
+                    value: Value::String("100_0000000"),
+                },
+                Entry {
+                    line: 7,
+                    table: Some("ram"),
+                    key: "first",
+                    value: Value::Number(16),
+                },
+                Entry {
+                    line: 8,
+                    table: Some("ram"),
+                    key: "SCREEN",
+                    value: Value::Array(vec![16384, 0x5fff]),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            parse("[alu]", &["comp"]),
+            Err("line 1: unknown table `[alu]`".to_string())
+        );
+        assert_eq!(
+            parse("D*A = \"1\"", &[]),
+            Err("line 1: expected `key = value`".to_string())
+        );
+        assert_eq!(
+            parse("\nkey = value", &[]),
+            Err("line 2: invalid value `value`".to_string())
+        );
+        assert_eq!(
+            parse("key = 0x10000", &[]),
+            Err("line 1: number `0x10000` doesn't fit in 16 bits".to_string())
+        );
+        let entries = parse("key = 1", &[]).unwrap();
+        assert_eq!(
+            entries[0].string(),
+            Err("line 1: `key` needs a quoted string".to_string())
+        );
+        assert_eq!(
+            entries[0].range(),
+            Err(
+                "line 1: `key` needs the first and last address, like `[0x4000, 0x5FFF]`"
+                    .to_string()
+            )
+        );
+    }
+}